@@ -0,0 +1,5343 @@
+//! Downloads tracks, albums, playlists, artists and podcasts from Spotify with librespot and
+//! tags the saved files. The `librespot-downloader` binary is a command line front end for
+//! [`Downloader`].
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+    fs::{File, OpenOptions, create_dir_all, read_dir, remove_dir, remove_file, rename, write},
+    future::Future,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write, copy},
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock, RwLock, atomic::{AtomicU32, AtomicUsize, Ordering}},
+    time::{Duration, Instant}
+};
+use librespot::{
+    core::{
+        Error, FileId, SpotifyId, SpotifyUri,
+        authentication::Credentials,
+        cache::Cache,
+        date::Date,
+        session::Session,
+        error::ErrorKind,
+        http_client::{HttpClient, HttpClientError}
+    },
+    audio::{AudioDecrypt, AudioFile},
+    protocol::authentication::AuthenticationType,
+    metadata::{
+        Album, Artist, Episode, Lyrics, Metadata, Playlist, Show, Track,
+        album::AlbumType,
+        artist::ArtistRole,
+        copyright::CopyrightType,
+        external_id::ExternalIds,
+        image::{self, Images},
+        lyrics::SyncType,
+        restriction::Restrictions,
+        audio::{AudioFileFormat, AudioFiles},
+    },
+    protocol::{context_page::ContextPage, playlist4_external::SelectedListContent}
+};
+use log::{debug, error, info, warn};
+use lofty::{
+    config::WriteOptions,
+    picture::{MimeType, Picture, PictureType},
+    prelude::*,
+    tag::{ItemKey, ItemValue, Tag, TagItem, TagType}
+};
+use ::image::{codecs::jpeg::JpegEncoder, imageops::FilterType};
+use http::{HeaderName, HeaderValue, Method, Request, StatusCode, header::{ACCEPT, AUTHORIZATION, USER_AGENT}};
+use http_body_util::{BodyExt, Full};
+use hyper_proxy2::{Intercept, Proxy, ProxyConnector};
+use hyper_tls::HttpsConnector;
+use hyper_util::{client::legacy::{Client, connect::HttpConnector}, rt::TokioExecutor};
+use bytes::Bytes;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use futures::stream::{self, StreamExt};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use sysinfo::Disks;
+use tokio::{sync::{Mutex, OnceCell, mpsc}, task, time::sleep};
+use tokio_util::sync::CancellationToken;
+use protobuf::Message;
+
+const PARTIAL_EXTENSION: &str = "part";
+const COPY_BUFFER_SIZE: usize = 256 * 1024;
+const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
+const SPOTIFY_OGG_LOUDNESS_OFFSET: u64 = 0x90;
+const OGG_MAGIC: &[u8; 4] = b"OggS";
+/// Header type flag of the first page of a logical Ogg stream
+const OGG_BEGINNING_OF_STREAM: u8 = 0x02;
+/// Bytes at the start of an Ogg download searched for the page beginning the Vorbis stream
+const OGG_SEARCH_LIMIT: u64 = 64 * 1024;
+const FLAC_MAGIC: &[u8; 4] = b"fLaC";
+/// Header of the STREAMINFO block that has to follow the FLAC magic
+const FLAC_STREAMINFO_HEADER: [[u8; 4]; 2] = [[0x00, 0x00, 0x00, 0x22], [0x80, 0x00, 0x00, 0x22]];
+const FLAC_SEARCH_LIMIT: u64 = 64 * 1024;
+const IMAGE_URL: &str = "https://i.scdn.co/image/";
+/// `User-Agent` of cover requests unless one is given with the cover headers
+const COVER_USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/124.0.0.0 Safari/537.36";
+const SPOTIFY_TRACK_ID_KEY: &str = "SPOTIFY_TRACK_ID";
+const SPOTIFY_URL_KEY: &str = "SPOTIFY_URL";
+const PRODUCER_KEY: &str = "PRODUCER";
+/// Spotify format a file was downloaded in, like `FLAC_FLAC_24BIT`
+const SPOTIFY_FORMAT_KEY: &str = "SPOTIFY_FORMAT";
+/// Popularity of a track from 0 to 100, see `DownloaderConfig::extended_tags`
+const SPOTIFY_POPULARITY_KEY: &str = "SPOTIFY_POPULARITY";
+/// When a track was added to the playlist or Liked Songs it was downloaded from
+const SPOTIFY_ADDED_KEY: &str = "SPOTIFY_ADDED";
+/// Encoder software every file is tagged with
+const ENCODER: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
+const SPOTIFY_URL: &str = "https://open.spotify.com/";
+/// Media tag of every download, as MusicBrainz names digital releases
+const MEDIA_TYPE: &str = "Digital Media";
+const ARTIST_API_URL: &str = "https://api.spotify.com/v1/artists/";
+const SEARCH_API_URL: &str = "https://api.spotify.com/v1/search";
+pub const DEFAULT_CONCURRENCY: usize = 4;
+pub const DEFAULT_COVER_QUALITY: u8 = 90;
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_RETRY_ATTEMPTS: u32 = 3;
+pub const DEFAULT_RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "{artist} - {title} ({id})";
+pub const FILENAME_PLACEHOLDERS: [&str; 7] = ["artist", "album", "title", "track", "disc", "id", "year"];
+const VARIOUS_ARTISTS: &str = "Various Artists";
+pub const DEFAULT_ARTIST_SEPARATOR: &str = " & ";
+pub const DEFAULT_ALBUM_ARTIST_SEPARATOR: &str = " & ";
+pub const DIRECTORY_PLACEHOLDERS: [&str; 5] = ["album_artist", "artist", "album", "disc", "year"];
+const MAX_FILENAME_BYTES: usize = 200;
+const RESERVED_FILENAME_CHARS: [char; 9] = ['/', '\\', ':', '*', '?', '"', '<', '>', '|'];
+const RESERVED_FILENAMES: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL",
+    "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9",
+    "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+const PLAYLIST_PAGE_SIZE: usize = 100;
+const SAVED_TRACKS_DIRECTORY: &str = "Liked Songs";
+pub const DEFAULT_RELEASE_TYPES: [ReleaseType; 3] = [ReleaseType::Album, ReleaseType::Single, ReleaseType::Ep];
+pub const FORMAT_PREFERENCE: [AudioFileFormat; 19] = [
+    AudioFileFormat::FLAC_FLAC_24BIT,   // 1. Lossless, 24-bit high resolution
+    AudioFileFormat::FLAC_FLAC,         // 2. Standard lossless FLAC
+    AudioFileFormat::AAC_320,           // 3. High-bitrate AAC (excellent perceptual quality)
+    AudioFileFormat::MP3_320,           // 4. Highest-bitrate MP3 (widely compatible)
+    AudioFileFormat::MP3_256,           // 5. Mid-high MP3 bitrate
+    AudioFileFormat::OGG_VORBIS_320,    // 6. High-quality Vorbis (slightly less efficient than AAC)
+    AudioFileFormat::AAC_160,           // 7. Medium-bitrate AAC
+    AudioFileFormat::MP3_160_ENC,       // 8. Possibly a special encoder variant, quality similar to MP3_160
+    AudioFileFormat::MP3_160,           // 9. Standard MP3 midrange quality
+    AudioFileFormat::OGG_VORBIS_160,    // 10. Mid-bitrate Vorbis
+    AudioFileFormat::MP4_128,           // 11. Medium-low quality (likely AAC in MP4 container)
+    AudioFileFormat::AAC_48,            // 12. Low-quality AAC variant
+    AudioFileFormat::AAC_24,            // 13. Very low bitrate AAC
+    AudioFileFormat::XHE_AAC_24,        // 14. xHE-AAC at 24 kbps — better compression than plain AAC_24
+    AudioFileFormat::XHE_AAC_16,        // 15. Lower bitrate xHE-AAC
+    AudioFileFormat::XHE_AAC_12,        // 16. Minimal bitrate, speech quality only
+    AudioFileFormat::OGG_VORBIS_96,     // 17. Low-quality Vorbis
+    AudioFileFormat::MP3_96,            // 18. Low-quality MP3
+    AudioFileFormat::OTHER5,            // 19. Unknown/legacy format, last resort
+];
+
+pub fn get_extension_from_format(format: AudioFileFormat) -> String {
+    let extension = match format {
+        AudioFileFormat::OGG_VORBIS_96
+        | AudioFileFormat::OGG_VORBIS_160
+        | AudioFileFormat::OGG_VORBIS_320 => "ogg",
+        AudioFileFormat::MP3_96
+        | AudioFileFormat::MP3_160
+        | AudioFileFormat::MP3_256
+        | AudioFileFormat::MP3_320
+        | AudioFileFormat::MP3_160_ENC => "mp3",
+        // Spotify delivers every AAC variant inside an MP4 container rather than as raw ADTS
+        AudioFileFormat::AAC_24
+        | AudioFileFormat::AAC_48
+        | AudioFileFormat::AAC_160
+        | AudioFileFormat::AAC_320
+        | AudioFileFormat::MP4_128
+        | AudioFileFormat::XHE_AAC_12
+        | AudioFileFormat::XHE_AAC_16
+        | AudioFileFormat::XHE_AAC_24 => "m4a",
+        AudioFileFormat::FLAC_FLAC | AudioFileFormat::FLAC_FLAC_24BIT => "flac",
+        _ => "bin",
+    };
+    String::from(extension)
+}
+
+/// Average data rate of `format` in bytes per second. It sizes the read-ahead librespot
+/// requests while streaming the file and the estimated size of downloads.
+pub fn format_data_rate(format: AudioFileFormat) -> usize {
+    // KiB/s, i.e. the nominal kbit/s over 8
+    let kib_per_second = match format {
+        AudioFileFormat::OGG_VORBIS_96 => 12.,
+        AudioFileFormat::OGG_VORBIS_160 => 20.,
+        AudioFileFormat::OGG_VORBIS_320 => 40.,
+        AudioFileFormat::MP3_256 => 32.,
+        AudioFileFormat::MP3_320 => 40.,
+        AudioFileFormat::MP3_160 => 20.,
+        AudioFileFormat::MP3_96 => 12.,
+        AudioFileFormat::MP3_160_ENC => 20.,
+        AudioFileFormat::AAC_24 => 3.,
+        AudioFileFormat::AAC_48 => 6.,
+        AudioFileFormat::AAC_160 => 20.,
+        AudioFileFormat::AAC_320 => 40.,
+        AudioFileFormat::MP4_128 => 16.,
+        AudioFileFormat::OTHER5 => 40.,
+        AudioFileFormat::FLAC_FLAC => 112., // assume 900 kbit/s on average
+        AudioFileFormat::XHE_AAC_12 => 1.5,
+        AudioFileFormat::XHE_AAC_16 => 2.,
+        AudioFileFormat::XHE_AAC_24 => 3.,
+        AudioFileFormat::FLAC_FLAC_24BIT => 256., // assume 2 Mbit/s on average
+    };
+    let data_rate: f32 = kib_per_second * 1024.;
+    data_rate.ceil() as usize
+}
+
+fn uri_from_parts(kind: &str, base62: &str) -> Result<SpotifyUri, Error> {
+    let id = SpotifyId::from_base62(base62)
+        .map_err(|_| Error::invalid_argument(format!("invalid Spotify id: {base62}")))?;
+    let uri = match kind {
+        "album" => SpotifyUri::Album { id },
+        "track" => SpotifyUri::Track { id },
+        "playlist" => SpotifyUri::Playlist { user: None, id },
+        "artist" => SpotifyUri::Artist { id },
+        "show" => SpotifyUri::Show { id },
+        "episode" => SpotifyUri::Episode { id },
+        _ => return Err(Error::invalid_argument(format!("unsupported Spotify reference type: {kind}"))),
+    };
+    Ok(uri)
+}
+
+/// Parses an open.spotify.com link, a `spotify:` URI or a bare album id.
+pub fn parse_reference(input: &str) -> Result<SpotifyUri, Error> {
+    let input = input.trim();
+    if input.starts_with("spotify:") {
+        return match SpotifyUri::from_uri(input) {
+            Ok(SpotifyUri::Unknown { kind, .. }) => {
+                Err(Error::invalid_argument(format!("unsupported Spotify reference type: {kind}")))
+            }
+            Ok(SpotifyUri::Local { .. }) => {
+                Err(Error::invalid_argument(format!("local files cannot be downloaded: {input}")))
+            }
+            Ok(uri) => Ok(uri),
+            Err(_) => Err(Error::invalid_argument(format!("invalid Spotify URI: {input}"))),
+        };
+    }
+
+    let url = input
+        .strip_prefix("https://")
+        .or_else(|| input.strip_prefix("http://"))
+        .unwrap_or(input);
+    if let Some(path) = url.strip_prefix("open.spotify.com/") {
+        let path = path.split(['?', '#']).next().unwrap_or_default();
+        let mut segments = path
+            .split('/')
+            .filter(|segment| !segment.is_empty() && !segment.starts_with("intl-"));
+        return match (segments.next(), segments.next()) {
+            (Some(kind), Some(id)) => uri_from_parts(kind, id),
+            _ => Err(Error::invalid_argument(format!("unrecognized Spotify link: {input}"))),
+        };
+    }
+
+    uri_from_parts("album", input)
+        .map_err(|_| Error::invalid_argument(format!("unrecognized Spotify reference: {input}")))
+}
+
+/// Selects the earliest format of `preference` out of `files`, or `None` when none of the
+/// available formats is preferred.
+pub fn select_format(
+    uri: &SpotifyUri,
+    files: &AudioFiles,
+    preference: &[AudioFileFormat]
+) -> Option<(AudioFileFormat, FileId)> {
+    files.iter().for_each(|file| {
+        debug!("<{}> has format {:?}", uri, file.0);
+    });
+
+    match preference
+        .iter()
+        .find_map(|format| {
+            files
+            .get(format)
+            .map(|file_id| (*format, *file_id))
+        })
+    {
+        Some(format) => {
+            debug!("<{}> selected format {:?}", uri, &format.0);
+            Some(format)
+        },
+        None => {
+            warn!("<{}> is not available in any supported format", uri);
+            None
+        }
+    }
+}
+
+/// Parses a format name like `FLAC_FLAC` or `mp3_320`, accepting only the formats listed in
+/// `FORMAT_PREFERENCE`.
+pub fn parse_format(name: &str) -> Result<AudioFileFormat, String> {
+    FORMAT_PREFERENCE
+        .iter()
+        .find(|format| format!("{format:?}").eq_ignore_ascii_case(name.trim()))
+        .copied()
+        .ok_or_else(|| {
+            let names: Vec<String> = FORMAT_PREFERENCE.iter().map(|format| format!("{format:?}")).collect();
+            format!("unsupported format {name}, expected one of: {}", names.join(", "))
+        })
+}
+
+/// Parses a `Name: value` HTTP header.
+pub fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, value) = header.split_once(':').ok_or_else(|| format!("expected Name: value, got {header:?}"))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|e| format!("invalid header name: {e}"))?;
+    let value = HeaderValue::from_str(value.trim()).map_err(|e| format!("invalid header value: {e}"))?;
+    Ok((name, value))
+}
+
+pub fn parse_cover_size(size: &str) -> Result<image::ImageSize, String> {
+    match size.to_ascii_lowercase().as_str() {
+        "small" => Ok(image::ImageSize::SMALL),
+        "large" => Ok(image::ImageSize::LARGE),
+        "xlarge" => Ok(image::ImageSize::XLARGE),
+        _ => Err(format!("unknown cover size {size}, expected one of: small, large, xlarge")),
+    }
+}
+
+/// Artists credited on `track` joined with `separator`. Featured artists, as told by their
+/// role, are left out with `FeaturedArtists::Omit`, and with `FeaturedArtists::OmitNamed` when
+/// the title already names them like "Song (feat. Artist)".
+fn join_artists(track: &Track, separator: &str, featured: FeaturedArtists) -> String {
+    let title = track.name.to_lowercase();
+    let is_featured = |id: &SpotifyUri| {
+        track
+            .artists_with_role
+            .iter()
+            .any(|artist| artist.id == *id && artist.role == ArtistRole::ARTIST_ROLE_FEATURED_ARTIST)
+    };
+    let artists: Vec<&str> = track
+        .artists
+        .iter()
+        .filter(|artist| match featured {
+            FeaturedArtists::Keep => true,
+            _ if !is_featured(&artist.id) => true,
+            FeaturedArtists::Omit => false,
+            FeaturedArtists::OmitNamed => !title.contains(&artist.name.to_lowercase()),
+        })
+        .map(|artist| artist.name.as_str())
+        .collect();
+    if artists.is_empty() {
+        return track.artists.iter().map(|artist| artist.name.as_str()).collect::<Vec<_>>().join(separator);
+    }
+    artists.join(separator)
+}
+
+/// Value of the external id of kind `external_type`, e.g. `isrc` or `upc`.
+fn external_id<'a>(external_ids: &'a ExternalIds, external_type: &str) -> Option<&'a str> {
+    external_ids
+        .iter()
+        .find(|external_id| external_id.external_type.eq_ignore_ascii_case(external_type))
+        .map(|external_id| external_id.id.as_str())
+        .filter(|id| !id.is_empty())
+}
+
+/// Copyright notices of `album` joined into one line, each prefixed with © or ℗ unless the
+/// text already carries the symbol.
+fn copyright_message(album: &Album) -> Option<String> {
+    let notices: Vec<String> = album
+        .copyrights
+        .iter()
+        .map(|copyright| (copyright.copyright_type, copyright.text.trim()))
+        .filter(|(_, text)| !text.is_empty())
+        .map(|(copyright_type, text)| {
+            let symbol = match copyright_type {
+                CopyrightType::C => "©",
+                CopyrightType::P => "℗",
+            };
+            let lowercase = text.to_lowercase();
+            let marked = text.starts_with(['©', '℗']) || lowercase.starts_with("(c)") || lowercase.starts_with("(p)");
+            if marked { text.to_string() } else { format!("{symbol} {text}") }
+        })
+        .collect();
+    (!notices.is_empty()).then(|| notices.join("; "))
+}
+
+/// Whether `album` is a compilation going by its type or a "Various Artists" album artist.
+fn is_compilation(album: &Album) -> bool {
+    album.album_type == AlbumType::COMPILATION
+        || album.artists.iter().any(|artist| artist.name.eq_ignore_ascii_case(VARIOUS_ARTISTS))
+}
+
+fn join_album_artists(album: &Album, separator: &str) -> String {
+    album.artists.iter().map(|a| a.name.as_str()).collect::<Vec<_>>().join(separator)
+}
+
+enum TemplatePart<'a> {
+    Literal(&'a str),
+    Placeholder { name: &'a str, width: usize },
+}
+
+/// Splits `template` into literal text and `{name}` or zero-padded `{name:02}` placeholders,
+/// rejecting names not listed in `placeholders`.
+fn parse_template<'a>(template: &'a str, placeholders: &[&str]) -> Result<Vec<TemplatePart<'a>>, Error> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            parts.push(TemplatePart::Literal(&rest[..start]));
+        }
+        let Some(end) = rest[start..].find('}') else {
+            return Err(Error::invalid_argument(format!("unterminated placeholder in template: {template}")));
+        };
+        let placeholder = &rest[start + 1..start + end];
+        let (name, width) = match placeholder.split_once(':') {
+            Some((name, width)) => {
+                let width = width.parse().map_err(|_| {
+                    Error::invalid_argument(format!("invalid width in template placeholder {{{placeholder}}}"))
+                })?;
+                (name, width)
+            }
+            None => (placeholder, 0),
+        };
+        if !placeholders.contains(&name) {
+            return Err(Error::invalid_argument(format!(
+                "unknown template placeholder {{{name}}}, expected one of: {}",
+                placeholders.join(", ")
+            )));
+        }
+        parts.push(TemplatePart::Placeholder { name, width });
+        rest = &rest[start + end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Literal(rest));
+    }
+    Ok(parts)
+}
+
+fn render_template(
+    template: &str,
+    placeholders: &[&str],
+    value: impl Fn(&str) -> String
+) -> Result<String, Error> {
+    let mut rendered = String::new();
+    for part in parse_template(template, placeholders)? {
+        match part {
+            TemplatePart::Literal(text) => rendered.push_str(text),
+            TemplatePart::Placeholder { name, width } => {
+                rendered.push_str(&format!("{:0>width$}", value(name)));
+            }
+        }
+    }
+    Ok(rendered)
+}
+
+/// Value of placeholder `name` for `track`, whose artists were joined into `artists`
+fn track_placeholder(track: &Track, artists: &str, name: &str) -> String {
+    match name {
+        "artist" => artists.to_string(),
+        "album_artist" => {
+            let album_artists = join_album_artists(&track.album, " & ");
+            if album_artists.is_empty() { artists.to_string() } else { album_artists }
+        }
+        "album" => track.album.name.clone(),
+        "title" => track.name.clone(),
+        "track" => track.number.to_string(),
+        "disc" => track.disc_number.to_string(),
+        "id" => track.id.to_id().unwrap_or_default(),
+        "year" => track.album.date.year().to_string(),
+        _ => String::new(),
+    }
+}
+
+fn episode_placeholder(episode: &Episode, name: &str) -> String {
+    match name {
+        "artist" | "album_artist" | "album" => episode.show_name.clone(),
+        "title" => episode.name.clone(),
+        "track" => episode.number.to_string(),
+        "disc" => String::from("1"),
+        "id" => episode.id.to_id().unwrap_or_default(),
+        "year" => episode.publish_time.year().to_string(),
+        _ => String::new(),
+    }
+}
+
+/// Turns `name` into a single path component that is valid on common filesystems: reserved
+/// characters and control characters become `_`, whitespace is collapsed, leading and trailing
+/// dots are dropped and the result is truncated to `MAX_FILENAME_BYTES` including `extension`.
+/// Pass an empty `extension` for directory names.
+pub fn sanitize_filename(name: &str, extension: &str) -> String {
+    let replaced: String = name
+        .chars()
+        .map(|c| if RESERVED_FILENAME_CHARS.contains(&c) || c.is_control() { '_' } else { c })
+        .collect();
+    let mut stem = replaced
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .trim_matches(|c: char| c == '.' || c.is_whitespace())
+        .to_string();
+
+    let max_stem = MAX_FILENAME_BYTES.saturating_sub(extension.len() + 1);
+    if stem.len() > max_stem {
+        let mut end = max_stem;
+        while !stem.is_char_boundary(end) {
+            end -= 1;
+        }
+        stem.truncate(end);
+        stem = stem.trim_end_matches(|c: char| c == '.' || c.is_whitespace()).to_string();
+    }
+    let base = stem.split('.').next().unwrap_or_default();
+    if stem.is_empty() || RESERVED_FILENAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(base)) {
+        stem.push('_');
+    }
+
+    if extension.is_empty() {
+        stem
+    } else {
+        format!("{stem}.{extension}")
+    }
+}
+
+/// Evaluates a directory template below `dirpath`, treating every `/` separated segment as
+/// one directory. Empty segments are dropped so an empty template keeps files in `dirpath`.
+/// Slashes inside placeholder values never start a new directory.
+fn render_directory(template: &str, dirpath: &Path, value: impl Fn(&str) -> String) -> Result<PathBuf, Error> {
+    let rendered = render_template(template, &DIRECTORY_PLACEHOLDERS, |name| value(name).replace('/', "_"))?;
+    let mut dirpath = dirpath.to_path_buf();
+    for segment in rendered.split('/').filter(|segment| !segment.trim().is_empty()) {
+        dirpath.push(sanitize_filename(segment, ""));
+    }
+    Ok(dirpath)
+}
+
+fn track_filepath(
+    template: &str,
+    track: &Track,
+    artists: &str,
+    format: AudioFileFormat,
+    dirpath: &Path
+) -> Result<PathBuf, Error> {
+    let file_extension = get_extension_from_format(format);
+    let filename = render_template(template, &FILENAME_PLACEHOLDERS, |name| track_placeholder(track, artists, name))?;
+    Ok(dirpath.join(sanitize_filename(&filename, &file_extension)))
+}
+
+fn episode_filepath(episode: &Episode, format: AudioFileFormat, dirpath: &Path) -> Result<PathBuf, Error> {
+    let file_extension = get_extension_from_format(format);
+    let filename = format!("{} - {} ({})", episode.show_name, episode.name, episode.id.to_id()?);
+    Ok(dirpath.join(sanitize_filename(&filename, &file_extension)))
+}
+
+/// Covers to tag `track` with: those of the release embedded in its own metadata, which has
+/// distinct art when it was relinked, swapped for another version or belongs to a deluxe
+/// edition, and those of `album` when the embedded release lists none
+fn track_covers<'a>(track: &'a Track, album: &'a Album) -> &'a Images {
+    if track.album.covers.is_empty() { &album.covers } else { &track.album.covers }
+}
+
+/// Release date of `album` as `YYYY-MM-DD`, or just `YYYY` when only the year is known.
+/// librespot fills a missing month and day with January 1st, so that date is written as a
+/// year. Albums without a date at all yield `None`.
+fn release_date(album: &Album) -> Option<String> {
+    let date = &album.date;
+    if date.year() <= 0 {
+        return None;
+    }
+    if date.month() as u8 == 1 && date.day() == 1 {
+        return Some(format!("{:04}", date.year()));
+    }
+    Some(format!("{:04}-{:02}-{:02}", date.year(), date.month() as u8, date.day()))
+}
+
+/// `m:ss`, or `h:mm:ss` from an hour on
+fn format_duration(milliseconds: i32) -> String {
+    let seconds = milliseconds.max(0) / 1000;
+    match seconds / 3600 {
+        0 => format!("{}:{:02}", seconds / 60, seconds % 60),
+        hours => format!("{hours}:{:02}:{:02}", seconds / 60 % 60, seconds % 60),
+    }
+}
+
+/// Names of the formats in `files`, ordered like `FORMAT_PREFERENCE`
+fn format_names(files: &AudioFiles) -> String {
+    let mut formats: Vec<AudioFileFormat> = files.keys().copied().collect();
+    formats.sort_by_key(|format| FORMAT_PREFERENCE.iter().position(|preferred| preferred == format));
+    if formats.is_empty() {
+        return "none".to_string();
+    }
+    formats.iter().map(|format| format!("{format:?}")).collect::<Vec<_>>().join(", ")
+}
+
+/// Rows of the formats in `files` with their extension and data rate, ordered like
+/// `FORMAT_PREFERENCE`. The format `preference` picks is marked as selected.
+fn format_rows(files: &AudioFiles, preference: &[AudioFileFormat]) -> Vec<[String; 4]> {
+    let mut formats: Vec<AudioFileFormat> = files.keys().copied().collect();
+    formats.sort_by_key(|format| FORMAT_PREFERENCE.iter().position(|preferred| preferred == format));
+    let selected = preference.iter().find(|format| files.contains_key(format));
+    formats
+        .into_iter()
+        .map(|format| {
+            [
+                format!("{format:?}"),
+                get_extension_from_format(format),
+                format!("{} kbit/s", format_data_rate(format) * 8 / 1024),
+                if selected == Some(&format) { "yes" } else { "" }.to_string(),
+            ]
+        })
+        .collect()
+}
+
+/// Left-aligned text table with a column per entry of `header`
+fn format_table<const N: usize>(header: [&str; N], rows: &[[String; N]]) -> String {
+    let mut widths = header.map(|title| title.chars().count());
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    let header = header.map(str::to_string);
+    for row in std::iter::once(&header).chain(rows) {
+        let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{cell:<width$}")).collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// Free-form tag key `name`, stored as a TXXX frame in Id3v2 and an iTunes atom in MP4.
+/// Items with these keys have to be added with `Tag::insert_unchecked`.
+fn custom_key(tag_type: TagType, name: &str) -> ItemKey {
+    match tag_type {
+        TagType::Mp4Ilst => ItemKey::Unknown(format!("----:com.apple.iTunes:{name}")),
+        _ => ItemKey::Unknown(name.to_string()),
+    }
+}
+
+/// Writer and producer names from a track's credits
+#[derive(Clone, Default)]
+struct Credits {
+    composers: Vec<String>,
+    lyricists: Vec<String>,
+    producers: Vec<String>,
+}
+
+impl Credits {
+    /// Reads the `roleCredits` of a credits view. Writers count as composers unless their
+    /// subroles only name them as lyricist.
+    fn parse(view: &serde_json::Value) -> Self {
+        let mut credits = Self::default();
+        let roles = view["roleCredits"].as_array().map(Vec::as_slice).unwrap_or_default();
+        for role in roles {
+            let title = role["roleTitle"].as_str().unwrap_or_default().to_lowercase();
+            let artists = role["artists"].as_array().map(Vec::as_slice).unwrap_or_default();
+            for artist in artists {
+                let Some(name) = artist["name"].as_str().map(str::trim).filter(|name| !name.is_empty()) else {
+                    continue;
+                };
+                let subroles: Vec<String> = artist["subroles"]
+                    .as_array()
+                    .map(Vec::as_slice)
+                    .unwrap_or_default()
+                    .iter()
+                    .filter_map(|subrole| subrole.as_str())
+                    .map(str::to_lowercase)
+                    .collect();
+                let lyricist = subroles.iter().any(|subrole| subrole.contains("lyricist"));
+                match title.as_str() {
+                    "writers" => {
+                        if !lyricist || subroles.iter().any(|subrole| !subrole.contains("lyricist")) {
+                            credits.composers.push(name.to_string());
+                        }
+                        if lyricist {
+                            credits.lyricists.push(name.to_string());
+                        }
+                    }
+                    "producers" => credits.producers.push(name.to_string()),
+                    _ => {}
+                }
+            }
+        }
+        credits
+    }
+
+    /// Adds the credits to `tag`. Tag types without a producer field get a custom one.
+    fn apply(&self, tag: &mut Tag) {
+        for composer in &self.composers {
+            tag.push(TagItem::new(ItemKey::Composer, ItemValue::Text(composer.clone())));
+        }
+        for lyricist in &self.lyricists {
+            tag.push(TagItem::new(ItemKey::Lyricist, ItemValue::Text(lyricist.clone())));
+        }
+        for producer in &self.producers {
+            if !tag.push(TagItem::new(ItemKey::Producer, ItemValue::Text(producer.clone()))) {
+                let key = custom_key(tag.tag_type(), PRODUCER_KEY);
+                tag.push_unchecked(TagItem::new(key, ItemValue::Text(producer.clone())));
+            }
+        }
+    }
+}
+
+/// Adds the duration in milliseconds, the media type and this downloader as the encoding
+/// software to `tag`. Tag types without such fields leave them out.
+fn push_media_info(tag: &mut Tag, duration_ms: i32) {
+    tag.insert(TagItem::new(ItemKey::Length, ItemValue::Text(duration_ms.max(0).to_string())));
+    tag.insert(TagItem::new(ItemKey::OriginalMediaType, ItemValue::Text(MEDIA_TYPE.to_string())));
+    tag.insert(TagItem::new(ItemKey::EncoderSoftware, ItemValue::Text(ENCODER.to_string())));
+}
+
+/// `date` as an ISO 8601 timestamp in UTC, `None` for the zero timestamp of unknown dates
+fn iso_timestamp(date: &Date) -> Option<String> {
+    let date = date.as_utc();
+    (date.unix_timestamp() != 0).then(|| {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            date.year(),
+            date.month() as u8,
+            date.day(),
+            date.hour(),
+            date.minute(),
+            date.second()
+        )
+    })
+}
+
+/// Records the Spotify `format` the audio was downloaded in, in its own field so comments stay
+/// free for the user
+fn push_format(tag: &mut Tag, format: AudioFileFormat) {
+    let format_key = custom_key(tag.tag_type(), SPOTIFY_FORMAT_KEY);
+    tag.insert_unchecked(TagItem::new(format_key, ItemValue::Text(format!("{format:?}"))));
+}
+
+/// The open.spotify.com link of `uri`
+fn spotify_url(uri: &SpotifyUri) -> Result<String, Error> {
+    Ok(format!("{SPOTIFY_URL}{}/{}", uri.item_type(), uri.to_id()?))
+}
+
+fn plain_lyrics(lyrics: &Lyrics) -> String {
+    lyrics.lyrics.lines.iter().map(|line| line.words.as_str()).collect::<Vec<_>>().join("\n")
+}
+
+/// Writes `cover.<ext>` into `dirpath` unless a cover with that name is already there, so
+/// tracks of the same album downloaded at the same time only write it once.
+fn save_cover_file(cover_data: &[u8], mime_type: &MimeType, dirpath: &Path) {
+    let extension = mime_type
+        .ext()
+        .or_else(|| infer::get(cover_data).map(|kind| kind.extension()))
+        .unwrap_or("jpg");
+    let coverpath = dirpath.join(format!("cover.{extension}"));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&coverpath)
+        .and_then(|mut file| file.write_all(cover_data));
+    match written {
+        Ok(()) => info!("Cover saved to {:?}", coverpath),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => debug!("Cover already saved at {:?}", coverpath),
+        Err(e) => warn!("Unable to write cover to {:?}: {}", coverpath, e),
+    }
+}
+
+/// Contents of the `album.json` written with `DownloaderConfig::save_metadata`
+#[derive(Serialize)]
+struct AlbumMetadata {
+    id: String,
+    name: String,
+    artists: Vec<String>,
+    album_type: String,
+    label: String,
+    release_date: Option<String>,
+    upc: Option<String>,
+    tracks: Vec<TrackMetadata>,
+}
+
+#[derive(Serialize)]
+struct TrackMetadata {
+    id: String,
+    name: String,
+    artists: Vec<String>,
+    disc_number: i32,
+    number: i32,
+    duration_ms: i32,
+    isrc: Option<String>,
+    explicit: bool,
+    formats: Vec<String>,
+}
+
+impl AlbumMetadata {
+    fn new(album: &Album, tracks: &[Track]) -> Self {
+        let uri = |uri: &SpotifyUri| uri.to_uri().unwrap_or_else(|_| uri.to_string());
+        AlbumMetadata {
+            id: uri(&album.id),
+            name: album.name.clone(),
+            artists: album.artists.iter().map(|artist| artist.name.clone()).collect(),
+            album_type: format!("{:?}", album.album_type),
+            label: album.label.trim().to_string(),
+            release_date: release_date(album),
+            upc: external_id(&album.external_ids, "upc").map(str::to_string),
+            tracks: tracks
+                .iter()
+                .map(|track| TrackMetadata {
+                    id: uri(&track.id),
+                    name: track.name.clone(),
+                    artists: track.artists.iter().map(|artist| artist.name.clone()).collect(),
+                    disc_number: track.disc_number,
+                    number: track.number,
+                    duration_ms: track.duration,
+                    isrc: external_id(&track.external_ids, "isrc").map(str::to_uppercase),
+                    explicit: track.is_explicit,
+                    formats: track.files.keys().map(|format| format!("{format:?}")).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// `text` with the characters XML reserves replaced by entities
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Writes an NFO file as read by Kodi and Jellyfin to `path`: a `root` element holding one
+/// child element per field, in order. Empty fields are left out.
+fn write_nfo(path: &Path, root: &str, fields: &[(&str, String)]) {
+    let mut xml = format!("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\"?>\n<{root}>\n");
+    for (name, value) in fields.iter().filter(|(_, value)| !value.is_empty()) {
+        xml.push_str(&format!("    <{name}>{}</{name}>\n", xml_escape(value)));
+    }
+    xml.push_str(&format!("</{root}>\n"));
+    match write(path, xml) {
+        Ok(()) => debug!("NFO saved to {:?}", path),
+        Err(e) => warn!("Unable to write NFO to {:?}: {e}", path),
+    }
+}
+
+/// Fields of the `<song>` NFO of a track, taken from the tags written to it
+fn track_nfo_fields(tag: &Tag) -> Vec<(&'static str, String)> {
+    let text = |key: ItemKey| tag.get_string(&key).unwrap_or_default().to_string();
+    let year = tag.get_string(&ItemKey::RecordingDate).or(tag.get_string(&ItemKey::Year)).unwrap_or_default();
+    let mut fields = vec![
+        ("title", text(ItemKey::TrackTitle)),
+        ("artist", text(ItemKey::TrackArtist)),
+        ("albumartist", text(ItemKey::AlbumArtist)),
+        ("album", text(ItemKey::AlbumTitle)),
+        ("track", text(ItemKey::TrackNumber)),
+        ("disc", text(ItemKey::DiscNumber)),
+        ("year", year.chars().take(4).collect()),
+    ];
+    fields.extend(tag.get_strings(&ItemKey::Genre).map(|genre| ("genre", genre.to_string())));
+    fields
+}
+
+/// `cover_data` re-encoded as JPEG with `quality`, scaled down to fit into `max_size` square
+/// pixels keeping its aspect ratio when given
+fn reencode_cover(cover_data: &[u8], max_size: Option<u32>, quality: u8) -> Result<Vec<u8>, Error> {
+    let mut cover = ::image::load_from_memory(cover_data).map_err(Error::failed_precondition)?;
+    if let Some(max_size) = max_size.filter(|&max_size| cover.width() > max_size || cover.height() > max_size) {
+        cover = cover.resize(max_size, max_size, FilterType::Lanczos3);
+    }
+    // JPEG has no alpha channel
+    let cover = cover.into_rgb8();
+    let mut jpeg = Vec::new();
+    cover.write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, quality)).map_err(Error::internal)?;
+    Ok(jpeg)
+}
+
+/// Saves `cover_data` to the cover cache at `path`, through a temporary file so concurrent runs
+/// never read a partial cover. Failures only cost a fetch next time.
+fn cache_cover(path: &Path, cover_data: &[u8]) {
+    let partpath = partial_path(path);
+    let written = path
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|()| write(&partpath, cover_data))
+        .and_then(|()| rename(&partpath, path));
+    if let Err(e) = written {
+        _ = remove_file(&partpath);
+        debug!("Unable to cache cover at {:?}: {e}", path);
+    }
+}
+
+/// Writes `<dirpath>/<name>.m3u8` listing the downloaded files of `outcomes` in order, with
+/// paths relative to `dirpath`. Skipped and failed tracks are left out.
+fn write_playlist_file(dirpath: &Path, name: &str, outcomes: &[(SpotifyUri, TrackOutcome)]) {
+    let mut m3u = String::from("#EXTM3U\n");
+    for (_, outcome) in outcomes {
+        let TrackOutcome::Downloaded(saved) = outcome else {
+            continue;
+        };
+        let path = saved.path.strip_prefix(dirpath).unwrap_or(&saved.path);
+        m3u.push_str(&format!("#EXTINF:{},{}\n{}\n", saved.duration.as_secs(), saved.title, path.display()));
+    }
+    let m3upath = dirpath.join(sanitize_filename(name, "m3u8"));
+    match write(&m3upath, m3u) {
+        Ok(()) => info!("Playlist saved to {:?}", m3upath),
+        Err(e) => warn!("Unable to write playlist to {:?}: {}", m3upath, e),
+    }
+}
+
+pub fn tag_type_for_extension(file_extension: &str) -> TagType {
+    match file_extension {
+        "ogg" | "flac" => TagType::VorbisComments,
+        "m4a" => TagType::Mp4Ilst,
+        _ => TagType::Id3v2,
+    }
+}
+
+/// Path a download is written to until it is complete, `<filepath>.part`
+fn partial_path(filepath: &Path) -> PathBuf {
+    let mut partpath = filepath.as_os_str().to_owned();
+    partpath.push(".");
+    partpath.push(PARTIAL_EXTENSION);
+    PathBuf::from(partpath)
+}
+
+/// Removes the partial download at `partpath` if `result` is an error.
+fn discard_partial<T>(partpath: &Path, result: Result<T, DownloadError>) -> Result<T, DownloadError> {
+    if result.is_err() {
+        _ = remove_file(partpath);
+    }
+    result
+}
+
+/// Removes partial downloads left behind by interrupted runs anywhere below `dirpath`,
+/// returning how many were removed.
+pub fn remove_partial_files(dirpath: &Path) -> usize {
+    let Ok(entries) = read_dir(dirpath) else {
+        return 0;
+    };
+    let mut removed = 0;
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            removed += remove_partial_files(&path);
+        } else if path.extension().is_some_and(|extension| extension == PARTIAL_EXTENSION) {
+            match remove_file(&path) {
+                Ok(()) => {
+                    debug!("Removed partial download {:?}", path);
+                    removed += 1;
+                }
+                Err(e) => warn!("Unable to remove partial download {:?}: {}", path, e),
+            }
+        }
+    }
+    removed
+}
+
+/// Writes `tag` to `filepath`, returning whether it succeeded. Failures are only logged since
+/// the audio itself is fine.
+fn save_tag(tag: &Tag, filepath: &Path) -> bool {
+    match tag.save_to_path(filepath, WriteOptions::default()) {
+        Ok(()) => {
+            debug!("Metadata written to {:?}", filepath);
+            true
+        }
+        Err(e) => {
+            warn!("Unable to write metadata to {:?}: {}", filepath, e);
+            false
+        }
+    }
+}
+
+/// Id embedded in the `(<id>).<ext>` suffix of a file name
+fn embedded_id(name: &str) -> Option<&str> {
+    let (stem, _) = name.rsplit_once('.')?;
+    let (_, id) = stem.strip_suffix(')')?.rsplit_once('(')?;
+    Some(id)
+}
+
+/// Collects the ids embedded in the `(<id>).<ext>` suffix of the files in `dirpath`.
+fn downloaded_ids(dirpath: &Path) -> HashSet<String> {
+    let Ok(entries) = read_dir(dirpath) else {
+        return HashSet::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| embedded_id(&entry.file_name().to_string_lossy()).map(str::to_string))
+        .collect()
+}
+
+/// Audio file in `dirpath` whose name embeds `id`, whatever format it was saved in. Partial
+/// downloads and lyrics are passed over.
+fn find_download(dirpath: &Path, id: &str) -> Option<PathBuf> {
+    read_dir(dirpath)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            let extension = path.extension().unwrap_or_default();
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            extension != PARTIAL_EXTENSION && extension != "lrc" && embedded_id(&name) == Some(id) && is_nonempty_file(path)
+        })
+}
+
+/// Files below `dirpath` that may hold audio, leaving out partial downloads and the lyrics,
+/// playlists, covers and metadata saved next to downloads
+fn audio_files(dirpath: &Path) -> Vec<PathBuf> {
+    const SIDECARS: [&str; 7] = ["lrc", "m3u8", "json", "nfo", "jpg", "png", "webp"];
+    let Ok(entries) = read_dir(dirpath) else {
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if entry.file_type().is_ok_and(|file_type| file_type.is_dir()) {
+            files.extend(audio_files(&path));
+            continue;
+        }
+        let extension = path.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+        if extension != PARTIAL_EXTENSION && !SIDECARS.contains(&extension.as_str()) && is_nonempty_file(&path) {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Copies the ReplayGain values and the Spotify format of the tag already in `filepath` into
+/// `tag`, since they come from the audio stream which a retag doesn't fetch.
+fn keep_stream_tags(tag: &mut Tag, filepath: &Path) {
+    let keys = [
+        ItemKey::ReplayGainTrackGain,
+        ItemKey::ReplayGainTrackPeak,
+        ItemKey::ReplayGainAlbumGain,
+        ItemKey::ReplayGainAlbumPeak,
+        custom_key(tag.tag_type(), SPOTIFY_FORMAT_KEY),
+    ];
+    let existing = match lofty::read_from_path(filepath) {
+        Ok(existing) => existing,
+        Err(e) => {
+            debug!("Unable to read the existing tags of {:?}: {e}", filepath);
+            return;
+        }
+    };
+    let Some(existing) = existing.tag(tag.tag_type()) else {
+        return;
+    };
+    for key in keys {
+        if let Some(item) = existing.get(&key) {
+            tag.insert(item.clone());
+        }
+    }
+}
+
+tokio::task_local! {
+    /// When the track or episode downloaded by the current task has to be finished, see
+    /// `DownloaderConfig::track_timeout`
+    static TRACK_DEADLINE: Instant;
+    /// What `Downloader::with_deadline` cleans up when the download of the current task times out
+    static TRACK_SCOPE: TrackScope;
+}
+
+/// State of a download running with `DownloaderConfig::track_timeout`
+#[derive(Default)]
+struct TrackScope {
+    /// Partial file the download writes to, removed when it times out
+    partial: RefCell<Option<PathBuf>>,
+    /// Cancelled when the download times out, stopping its blocking copy after the next read
+    stop: CancellationToken,
+    /// Cancelled by the blocking copy of the download once it has stopped, if one was started
+    copying: RefCell<Option<CancellationToken>>,
+}
+
+/// Carries the delay a rate-limited server asked for through the retry helper.
+#[derive(Debug)]
+struct RateLimited(Duration);
+
+impl fmt::Display for RateLimited {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limited, retry after {} ms", self.0.as_millis())
+    }
+}
+
+impl std::error::Error for RateLimited {}
+
+fn is_transient(error: &Error) -> bool {
+    matches!(
+        error.kind,
+        ErrorKind::Unavailable
+            | ErrorKind::DeadlineExceeded
+            | ErrorKind::ResourceExhausted
+            | ErrorKind::Aborted
+            | ErrorKind::Unknown
+    )
+}
+
+fn is_nonempty_file(path: &Path) -> bool {
+    path.metadata().is_ok_and(|metadata| metadata.len() > 0)
+}
+
+/// First of `<stem> (1).<ext>`, `<stem> (2).<ext>`, ... next to `path` that doesn't exist yet
+fn numbered_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let extension = path.extension().map(|extension| format!(".{}", extension.to_string_lossy())).unwrap_or_default();
+    (1..)
+        .map(|n| path.with_file_name(format!("{stem} ({n}){extension}")))
+        .find(|candidate| !candidate.exists())
+        .unwrap_or_else(|| path.to_path_buf())
+}
+
+/// Free space of the filesystem holding `path`, or its closest existing ancestor
+fn available_space(path: &Path) -> Option<u64> {
+    let path = path.ancestors().chain([Path::new(".")]).find_map(|ancestor| ancestor.canonicalize().ok())?;
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+/// New session for the account of `session`, logged in with the credentials in its cache, or
+/// without a cache with the reusable credentials `session` was given when it logged in
+async fn reconnect(session: &Session) -> Result<Session, Error> {
+    let cache = session.cache().map(|cache| Cache::clone(cache));
+    let credentials = match cache.as_ref().and_then(Cache::credentials) {
+        Some(credentials) => credentials,
+        None if !session.auth_data().is_empty() => Credentials {
+            username: Some(session.username()),
+            auth_type: AuthenticationType::AUTHENTICATION_STORED_SPOTIFY_CREDENTIALS,
+            auth_data: session.auth_data(),
+        },
+        None => return Err(Error::unavailable("no credentials to reconnect with")),
+    };
+    let store = cache.is_some();
+    let new = Session::new(session.config().clone(), cache);
+    new.connect(credentials, store).await?;
+    Ok(new)
+}
+
+/// Whether none of `restrictions` excludes the country code `market`
+fn is_available_in(restrictions: &Restrictions, market: &str) -> bool {
+    restrictions.iter().all(|restriction| {
+        let listed = |countries: &Vec<String>| countries.iter().any(|country| country == market);
+        restriction.countries_allowed.as_ref().is_none_or(listed)
+            && !restriction.countries_forbidden.as_ref().is_some_and(listed)
+    })
+}
+
+/// Exponential backoff for the given 1-based attempt, plus up to 50% random jitter.
+fn backoff_delay(base_delay: Duration, attempt: u32) -> Duration {
+    let delay = base_delay.saturating_mul(2u32.saturating_pow(attempt - 1));
+    let jitter = rand::random_range(0..=delay.as_millis() as u64 / 2);
+    delay + Duration::from_millis(jitter)
+}
+
+pub struct Subfile<T: Read + Seek> {
+    stream: T,
+    offset: u64,
+    length: u64,
+    position: u64,
+}
+
+impl<T: Read + Seek> Subfile<T> {
+    pub fn new(mut stream: T, offset: u64, length: u64) -> Result<Subfile<T>, io::Error> {
+        let target = SeekFrom::Start(offset);
+        stream.seek(target)?;
+
+        Ok(Subfile {
+            stream,
+            offset,
+            length,
+            position: offset,
+        })
+    }
+}
+
+impl<T: Read + Seek> Read for Subfile<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let end = self.offset + self.length;
+        if self.position >= end {
+            return Ok(0);
+        }
+        let remaining = (end - self.position).min(buf.len() as u64) as usize;
+        let read = self.stream.read(&mut buf[..remaining])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<T: Read + Seek> Seek for Subfile<T> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => SeekFrom::Start(offset + self.offset),
+            SeekFrom::End(offset) => {
+                let end = (self.offset + self.length) as i64;
+                if end + offset < self.offset as i64 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "newpos would be < self.offset",
+                    ));
+                }
+                SeekFrom::Start((end + offset) as u64)
+            }
+            SeekFrom::Current(offset) => {
+                if (self.position as i64 + offset) < self.offset as i64 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "newpos would be < self.offset",
+                    ));
+                }
+                pos
+            }
+        };
+
+        let newpos = self.stream.seek(pos)?;
+        self.position = newpos;
+        Ok(newpos - self.offset)
+    }
+}
+
+/// Position of the Vorbis stream in a decrypted Spotify Ogg file. It normally starts at
+/// `SPOTIFY_OGG_HEADER_END`, right after the page carrying Spotify's own header. Otherwise the
+/// first page beginning a stream within `OGG_SEARCH_LIMIT` bytes is taken, searching from the
+/// byte after the magic of that first page.
+fn ogg_offset<T: Read + Seek>(stream: &mut T) -> io::Result<Option<u64>> {
+    let mut head = Vec::new();
+    stream.seek(SeekFrom::Start(0))?;
+    stream.take(OGG_SEARCH_LIMIT).read_to_end(&mut head)?;
+    // Magic, stream structure version 0 and the header type of the page
+    let begins_stream = |window: &[u8]| {
+        window[..4] == OGG_MAGIC[..] && window[4] == 0 && window[5] & OGG_BEGINNING_OF_STREAM != 0
+    };
+    let expected = SPOTIFY_OGG_HEADER_END as usize;
+    if head.get(expected..expected + 6).is_some_and(begins_stream) {
+        return Ok(Some(SPOTIFY_OGG_HEADER_END));
+    }
+    let offset = head.windows(6).skip(1).position(begins_stream);
+    Ok(offset.map(|offset| offset as u64 + 1))
+}
+
+/// Position of the FLAC stream in the decrypted file, which is 0 unless the stream is preceded
+/// by other framing. Only a magic directly followed by a STREAMINFO block within the first
+/// `FLAC_SEARCH_LIMIT` bytes counts, so `fLaC` codes inside other containers aren't mistaken for
+/// the start of the stream.
+fn flac_offset<T: Read + Seek>(stream: &mut T) -> io::Result<Option<u64>> {
+    let mut head = Vec::new();
+    stream.seek(SeekFrom::Start(0))?;
+    stream.take(FLAC_SEARCH_LIMIT).read_to_end(&mut head)?;
+    let offset = head.windows(8).position(|window| {
+        window[..4] == FLAC_MAGIC[..] && FLAC_STREAMINFO_HEADER.iter().any(|header| window[4..] == header[..])
+    });
+    Ok(offset.map(|offset| offset as u64))
+}
+
+/// ReplayGain values Spotify stores in the header of its Ogg Vorbis files
+#[derive(Clone, Copy, Debug)]
+pub struct Loudness {
+    pub track_gain_db: f32,
+    pub track_peak: f32,
+    pub album_gain_db: f32,
+    pub album_peak: f32,
+}
+
+impl Loudness {
+    /// Reads the loudness data from the decrypted file, which has to start at the Spotify
+    /// specific header.
+    fn read<T: Read + Seek>(stream: &mut T) -> io::Result<Loudness> {
+        let mut buf = [0u8; 16];
+        stream.seek(SeekFrom::Start(SPOTIFY_OGG_LOUDNESS_OFFSET))?;
+        stream.read_exact(&mut buf)?;
+        let value = |index: usize| f32::from_le_bytes([buf[index], buf[index + 1], buf[index + 2], buf[index + 3]]);
+        Ok(Loudness {
+            track_gain_db: value(0),
+            track_peak: value(4),
+            album_gain_db: value(8),
+            album_peak: value(12),
+        })
+    }
+
+    fn apply(&self, tag: &mut Tag) {
+        let items = [
+            (ItemKey::ReplayGainTrackGain, format!("{:.2} dB", self.track_gain_db)),
+            (ItemKey::ReplayGainTrackPeak, format!("{:.6}", self.track_peak)),
+            (ItemKey::ReplayGainAlbumGain, format!("{:.2} dB", self.album_gain_db)),
+            (ItemKey::ReplayGainAlbumPeak, format!("{:.6}", self.album_peak)),
+        ];
+        for (key, value) in items {
+            tag.insert(TagItem::new(key, ItemValue::Text(value)));
+        }
+    }
+}
+
+/// A file written by a successful download
+#[derive(Clone, Debug)]
+pub struct SavedFile {
+    pub path: PathBuf,
+    pub format: AudioFileFormat,
+    /// Whether the metadata was written to the file
+    pub tagged: bool,
+    /// `Artist - Title`, as shown by players for playlist entries
+    pub title: String,
+    pub duration: Duration,
+    /// Whether `format` ranks below `DownloaderConfig::min_quality`
+    pub below_min_quality: bool,
+    /// What was done about a file that already existed at the planned path, `path` then holds
+    /// the renamed path with `OnExisting::Rename`
+    pub on_existing: Option<OnExisting>,
+}
+
+#[derive(Debug)]
+pub enum TrackOutcome {
+    Downloaded(SavedFile),
+    SkippedExisting(PathBuf),
+    SkippedUnsupportedFormat,
+    /// The best available format ranks below `DownloaderConfig::min_quality`, which
+    /// `DownloaderConfig::strict_quality` refuses
+    SkippedBelowMinQuality(AudioFileFormat),
+    Failed(DownloadError),
+    /// What a download would have saved, see `DownloaderConfig::dry_run`. `exists` tells
+    /// whether it would have been skipped as already downloaded.
+    DryRun { file: SavedFile, exists: bool },
+    /// The download was stopped through `Downloader::set_cancellation_token`
+    Cancelled,
+    /// The tags of the existing file were rewritten, see `DownloaderConfig::retag`
+    Retagged(PathBuf),
+    /// There is no existing file to retag, see `DownloaderConfig::retag`
+    SkippedNotDownloaded,
+    /// A recording with the same ISRC was already downloaded by this run as the given track,
+    /// see `DownloaderConfig::dedup_isrc`
+    SkippedDuplicate(SpotifyUri),
+}
+
+/// Progress of a running download, see `Downloader::events`
+#[derive(Clone, Debug)]
+pub enum DownloadEvent {
+    /// A track or episode is about to be downloaded
+    TrackStarted { uri: SpotifyUri, title: String },
+    /// `bytes` of the `total` bytes of audio have been saved
+    Progress { uri: SpotifyUri, bytes: u64, total: u64 },
+    TrackCompleted { uri: SpotifyUri, path: PathBuf },
+    /// The track was already downloaded, isn't available in a wanted format or quality, was
+    /// cancelled, has nothing to retag, duplicates another track or this is a dry run
+    TrackSkipped { uri: SpotifyUri },
+    TrackFailed { uri: SpotifyUri, error: String },
+}
+
+impl DownloadEvent {
+    fn finished(uri: &SpotifyUri, outcome: &TrackOutcome) -> Self {
+        let uri = uri.clone();
+        match outcome {
+            TrackOutcome::Downloaded(saved) => DownloadEvent::TrackCompleted { uri, path: saved.path.clone() },
+            TrackOutcome::Retagged(path) => DownloadEvent::TrackCompleted { uri, path: path.clone() },
+            TrackOutcome::SkippedExisting(_)
+            | TrackOutcome::SkippedUnsupportedFormat
+            | TrackOutcome::SkippedBelowMinQuality(_)
+            | TrackOutcome::DryRun { .. }
+            | TrackOutcome::Cancelled
+            | TrackOutcome::SkippedNotDownloaded
+            | TrackOutcome::SkippedDuplicate(_) => DownloadEvent::TrackSkipped { uri },
+            TrackOutcome::Failed(e) => DownloadEvent::TrackFailed { uri, error: e.to_string() },
+        }
+    }
+}
+
+/// Passes every read of `inner` on to `on_read` along with the number of bytes read so far.
+/// An error returned by `on_read` aborts the read.
+struct ReportingReader<R: Read, F: FnMut(u64) -> io::Result<()>> {
+    inner: R,
+    read: u64,
+    on_read: F,
+}
+
+impl<R: Read, F: FnMut(u64) -> io::Result<()>> Read for ReportingReader<R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.read += read as u64;
+        (self.on_read)(self.read)?;
+        Ok(read)
+    }
+}
+
+/// Token bucket shared by all downloads to keep their combined throughput below a rate
+struct Throttle {
+    /// Bytes that may pass right away, negative while earlier reads are being paid off
+    state: std::sync::Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    fn new() -> Self {
+        Self { state: std::sync::Mutex::new((0., Instant::now())) }
+    }
+
+    /// Blocks the calling thread until `bytes` may pass at `rate` bytes per second. Idle time
+    /// accumulates at most one second worth of bytes.
+    fn take(&self, bytes: u64, rate: u64) {
+        let rate = rate as f64;
+        let wait = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let (available, refilled) = &mut *state;
+            let now = Instant::now();
+            *available = (*available + now.duration_since(*refilled).as_secs_f64() * rate).min(rate);
+            *refilled = now;
+            *available -= bytes as f64;
+            Duration::from_secs_f64((-*available / rate).max(0.))
+        };
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+/// Why a download failed
+#[derive(Debug)]
+pub enum DownloadError {
+    /// The metadata of a track, album, playlist or other item couldn't be loaded
+    Metadata(Error),
+    /// The decryption key of an audio file was refused
+    AudioKey(Error),
+    /// None of the formats in `DownloaderConfig::format_preference` is available
+    UnsupportedFormat(SpotifyUri),
+    /// The best available format ranks below `DownloaderConfig::min_quality`
+    BelowMinQuality(SpotifyUri, AudioFileFormat),
+    /// A local file couldn't be read or written
+    Io(io::Error),
+    /// The tags of a downloaded file couldn't be assembled
+    Tag(Error),
+    /// An id, reference or template couldn't be used
+    InvalidInput(Error),
+    /// The download was cancelled before it completed
+    Cancelled,
+    /// The download took longer than `DownloaderConfig::track_timeout`
+    TimedOut,
+    /// The items of a collection are estimated to need more than the free disk space
+    InsufficientSpace { needed: u64, available: u64 },
+    /// An album, playlist or show has no tracks or episodes that could be downloaded
+    Empty(SpotifyUri),
+    /// A downloaded FLAC file didn't decode completely, see `DownloaderConfig::verify`
+    Corrupt(String),
+    /// A downloaded file couldn't be converted
+    #[cfg(feature = "transcode")]
+    Transcode(Error),
+    /// Any other failure of the Spotify session, like a dropped connection
+    Session(Error),
+}
+
+impl DownloadError {
+    /// Whether trying again later may succeed, as for network failures and rate limits
+    pub fn is_transient(&self) -> bool {
+        match self {
+            DownloadError::Metadata(e) | DownloadError::AudioKey(e) | DownloadError::Session(e) => is_transient(e),
+            DownloadError::Io(e) => matches!(e.kind(), io::ErrorKind::Interrupted | io::ErrorKind::TimedOut),
+            DownloadError::TimedOut => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Metadata(e) => write!(f, "unable to load metadata: {e}"),
+            DownloadError::AudioKey(e) => write!(f, "unable to load audio key: {e}"),
+            DownloadError::UnsupportedFormat(uri) => write!(f, "<{uri}> is not available in a wanted format"),
+            DownloadError::BelowMinQuality(uri, format) => {
+                write!(f, "<{uri}> is only available in {format:?}, below the minimum quality")
+            }
+            DownloadError::Cancelled => write!(f, "cancelled"),
+            DownloadError::TimedOut => write!(f, "timed out"),
+            DownloadError::InsufficientSpace { needed, available } => write!(
+                f,
+                "about {} are needed but only {} are free",
+                HumanBytes(*needed),
+                HumanBytes(*available)
+            ),
+            DownloadError::Empty(uri) => write!(f, "<{uri}> has nothing to download"),
+            DownloadError::Corrupt(reason) => write!(f, "verification failed: {reason}"),
+            DownloadError::Io(e) => write!(f, "file error: {e}"),
+            DownloadError::Tag(e) => write!(f, "unable to tag: {e}"),
+            DownloadError::InvalidInput(e) => write!(f, "{e}"),
+            #[cfg(feature = "transcode")]
+            DownloadError::Transcode(e) => write!(f, "unable to transcode: {e}"),
+            DownloadError::Session(e) => write!(f, "session error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DownloadError::UnsupportedFormat(_)
+            | DownloadError::BelowMinQuality(..)
+            | DownloadError::Cancelled
+            | DownloadError::TimedOut
+            | DownloadError::InsufficientSpace { .. }
+            | DownloadError::Empty(_)
+            | DownloadError::Corrupt(_) => None,
+            DownloadError::Io(e) => Some(e),
+            DownloadError::Metadata(e)
+            | DownloadError::AudioKey(e)
+            | DownloadError::Tag(e)
+            | DownloadError::InvalidInput(e)
+            | DownloadError::Session(e) => Some(e),
+            #[cfg(feature = "transcode")]
+            DownloadError::Transcode(e) => Some(e),
+        }
+    }
+}
+
+impl From<Error> for DownloadError {
+    fn from(e: Error) -> Self {
+        DownloadError::Session(e)
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(e: io::Error) -> Self {
+        DownloadError::Io(e)
+    }
+}
+
+/// What is done with the lyrics of downloaded tracks
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LyricsMode {
+    /// Write time-synced lyrics to a `.lrc` file next to the audio
+    Synced,
+    /// Embed the lyrics text into the audio tags
+    Plain,
+    /// Don't fetch lyrics
+    #[default]
+    None,
+}
+
+/// Which featured artists are credited in artist tags and filenames
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FeaturedArtists {
+    /// Credit every artist of the track
+    #[default]
+    Keep,
+    /// Leave out featured artists the title already names, like "Song (feat. Artist)"
+    OmitNamed,
+    /// Only credit the main artists
+    Omit,
+}
+
+/// How the audio of a download is fetched from the CDN
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Prefetch {
+    /// Request the whole file up front, since downloads read it from start to end anyway
+    #[default]
+    Whole,
+    /// Fetch a few seconds ahead of the read position, like librespot does during playback
+    Stream,
+}
+
+/// Kind of release in an artist's discography
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ReleaseType {
+    Album,
+    Single,
+    Ep,
+    Compilation,
+    /// Releases of other artists the artist appears on
+    #[value(alias = "appears_on")]
+    #[serde(alias = "appears_on")]
+    AppearsOn,
+}
+
+impl ReleaseType {
+    /// Type of `album`, listed in the appears on group of the artist when `appears_on`
+    fn of(album: &Album, appears_on: bool) -> Option<Self> {
+        if appears_on {
+            return Some(ReleaseType::AppearsOn);
+        }
+        match album.album_type {
+            AlbumType::ALBUM => Some(ReleaseType::Album),
+            AlbumType::SINGLE => Some(ReleaseType::Single),
+            AlbumType::EP => Some(ReleaseType::Ep),
+            AlbumType::COMPILATION => Some(ReleaseType::Compilation),
+            _ => None,
+        }
+    }
+}
+
+/// Which version of a track is downloaded when Spotify links an explicit and a clean one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Variant {
+    Explicit,
+    Clean,
+}
+
+impl Variant {
+    fn matches(self, track: &Track) -> bool {
+        track.is_explicit == (self == Variant::Explicit)
+    }
+}
+
+/// What is done when a file already exists where a download would be saved
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnExisting {
+    /// Keep the existing file and skip the download
+    #[default]
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Save the download next to it with a ` (1)`, ` (2)`, ... suffix
+    Rename,
+}
+
+/// Target format of `--transcode`
+#[cfg(feature = "transcode")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TranscodeFormat {
+    Mp3,
+    Opus,
+}
+
+#[cfg(feature = "transcode")]
+impl TranscodeFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            TranscodeFormat::Mp3 => "mp3",
+            TranscodeFormat::Opus => "opus",
+        }
+    }
+
+    fn codec(self) -> &'static str {
+        match self {
+            TranscodeFormat::Mp3 => "libmp3lame",
+            TranscodeFormat::Opus => "libopus",
+        }
+    }
+
+    fn tag_type(self) -> TagType {
+        match self {
+            TranscodeFormat::Mp3 => TagType::Id3v2,
+            TranscodeFormat::Opus => TagType::VorbisComments,
+        }
+    }
+}
+
+/// Uncompressed format lossless downloads are decoded to with `--pcm`
+#[cfg(feature = "pcm")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PcmFormat {
+    Wav,
+    Aiff,
+}
+
+#[cfg(feature = "pcm")]
+impl PcmFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            PcmFormat::Wav => "wav",
+            PcmFormat::Aiff => "aiff",
+        }
+    }
+
+    /// Decodes the FLAC file `filepath` next to itself and writes `tag` to the result as ID3v2,
+    /// which both formats carry in a chunk of their own.
+    async fn run(self, filepath: &Path, mut tag: Tag) -> Result<(), Error> {
+        let outpath = filepath.with_extension(self.extension());
+        let partpath = partial_path(&outpath);
+        let inpath = filepath.to_path_buf();
+        let decoded = {
+            let partpath = partpath.clone();
+            task::spawn_blocking(move || decode_flac(&inpath, &partpath, self)).await.map_err(Error::internal)?
+        };
+        if let Err(e) = decoded.and_then(|()| Ok(rename(&partpath, &outpath)?)) {
+            _ = remove_file(&partpath);
+            return Err(e);
+        }
+        tag.re_map(TagType::Id3v2);
+        save_tag(&tag, &outpath);
+        info!("Decoded {:?} to {:?}", filepath, outpath);
+        Ok(())
+    }
+}
+
+/// Decodes the FLAC file `inpath` into a `format` file at `outpath` with the same sample rate,
+/// channels and bit depth.
+#[cfg(feature = "pcm")]
+fn decode_flac(inpath: &Path, outpath: &Path, format: PcmFormat) -> Result<(), Error> {
+    let mut reader = claxon::FlacReader::open(inpath).map_err(Error::failed_precondition)?;
+    let info = reader.streaminfo();
+    match format {
+        PcmFormat::Wav => {
+            let spec = hound::WavSpec {
+                channels: info.channels as u16,
+                sample_rate: info.sample_rate,
+                bits_per_sample: info.bits_per_sample as u16,
+                sample_format: hound::SampleFormat::Int,
+            };
+            let mut writer = hound::WavWriter::create(outpath, spec).map_err(Error::internal)?;
+            for sample in reader.samples() {
+                writer.write_sample(sample.map_err(Error::failed_precondition)?).map_err(Error::internal)?;
+            }
+            writer.finalize().map_err(Error::internal)?;
+        }
+        PcmFormat::Aiff => {
+            // AIFF announces the number of frames before the samples
+            let frames = info
+                .samples
+                .and_then(|frames| u32::try_from(frames).ok())
+                .ok_or_else(|| Error::failed_precondition(format!("{:?} doesn't announce its length", inpath)))?;
+            let samples =
+                reader.samples().map(|sample| sample.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)));
+            let mut writer = BufWriter::new(File::create(outpath)?);
+            let (channels, bits_per_sample) = (info.channels as u16, info.bits_per_sample as u16);
+            write_aiff(&mut writer, channels, info.sample_rate, bits_per_sample, frames, samples)?;
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Decodes every frame of the FLAC file at `filepath`, which checks the checksum of each, and
+/// compares the decoded samples with the total STREAMINFO announces to catch truncated files
+fn verify_flac(filepath: &Path) -> Result<(), String> {
+    let mut reader = claxon::FlacReader::open(filepath).map_err(|e| e.to_string())?;
+    let expected = reader.streaminfo().samples;
+    let mut blocks = reader.blocks();
+    let mut buffer = Vec::new();
+    let mut decoded = 0;
+    while let Some(block) = blocks.read_next_or_eof(buffer).map_err(|e| format!("after {decoded} samples: {e}"))? {
+        decoded += u64::from(block.duration());
+        buffer = block.into_buffer();
+    }
+    match expected {
+        Some(expected) if expected != decoded => Err(format!("{decoded} of {expected} samples decoded")),
+        _ => Ok(()),
+    }
+}
+
+/// Writes `frames` frames of interleaved `samples` as an AIFF file: a FORM container with a
+/// COMM chunk describing the audio and an SSND chunk of big-endian samples. Fails when
+/// `samples` holds another number of frames.
+#[cfg(feature = "pcm")]
+fn write_aiff<W: Write>(
+    writer: &mut W,
+    channels: u16,
+    sample_rate: u32,
+    bits_per_sample: u16,
+    frames: u32,
+    samples: impl Iterator<Item = io::Result<i32>>
+) -> io::Result<()> {
+    let bytes_per_sample = bits_per_sample.div_ceil(8) as usize;
+    let sample_count = frames as u64 * channels as u64;
+    let data_len = u32::try_from(sample_count * bytes_per_sample as u64)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "too many samples for AIFF"))?;
+    // Chunks are padded to an even length
+    let pad = data_len % 2;
+    // FORM type, COMM chunk and SSND header with its offset and block size
+    let form_len = 4 + (8 + 18) + (8 + 8) + data_len + pad;
+    writer.write_all(b"FORM")?;
+    writer.write_all(&form_len.to_be_bytes())?;
+    writer.write_all(b"AIFF")?;
+    writer.write_all(b"COMM")?;
+    writer.write_all(&18u32.to_be_bytes())?;
+    writer.write_all(&channels.to_be_bytes())?;
+    writer.write_all(&frames.to_be_bytes())?;
+    writer.write_all(&bits_per_sample.to_be_bytes())?;
+    writer.write_all(&extended_float(sample_rate))?;
+    writer.write_all(b"SSND")?;
+    writer.write_all(&(8 + data_len).to_be_bytes())?;
+    writer.write_all(&[0; 8])?;
+    // Samples are left-aligned in their bytes
+    let shift = bytes_per_sample * 8 - bits_per_sample as usize;
+    let mut written = 0;
+    for sample in samples {
+        if written == sample_count {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("more than {frames} frames")));
+        }
+        let bytes = (sample? << shift).to_be_bytes();
+        writer.write_all(&bytes[4 - bytes_per_sample..])?;
+        written += 1;
+    }
+    if written != sample_count {
+        let e = format!("{} of {frames} frames", written / channels.max(1) as u64);
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+    }
+    if pad == 1 {
+        writer.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+/// `value` as the 80-bit IEEE 754 extended precision number AIFF stores sample rates in
+#[cfg(feature = "pcm")]
+fn extended_float(value: u32) -> [u8; 10] {
+    let mut bytes = [0; 10];
+    if value == 0 {
+        return bytes;
+    }
+    let exponent = 16383 + 31 - value.leading_zeros() as u16;
+    let mantissa = (value as u64) << (32 + value.leading_zeros());
+    bytes[..2].copy_from_slice(&exponent.to_be_bytes());
+    bytes[2..].copy_from_slice(&mantissa.to_be_bytes());
+    bytes
+}
+
+/// Progress of a run saved to a JSON file: the references still to download and the tracks
+/// and episodes already saved, so an interrupted run can be resumed where it stopped
+pub struct ResumeState {
+    path: PathBuf,
+    state: std::sync::Mutex<ResumeFile>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ResumeFile {
+    references: Vec<String>,
+    completed: HashMap<String, PathBuf>,
+}
+
+impl ResumeState {
+    /// Starts a new state at `path` with `references` still to download, replacing an earlier
+    /// state
+    pub fn create(path: &Path, references: Vec<String>) -> Result<Self, Error> {
+        let state = Self {
+            path: path.to_path_buf(),
+            state: std::sync::Mutex::new(ResumeFile { references, completed: HashMap::new() }),
+        };
+        state.save()?;
+        Ok(state)
+    }
+
+    /// Loads the state saved at `path`. A missing file yields an empty state.
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file = match std::fs::read(path) {
+            Ok(json) => serde_json::from_slice(&json).map_err(Error::failed_precondition)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => ResumeFile::default(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self { path: path.to_path_buf(), state: std::sync::Mutex::new(file) })
+    }
+
+    /// References not yet downloaded
+    pub fn references(&self) -> Vec<String> {
+        self.lock().references.clone()
+    }
+
+    /// Adds `reference` to the end of the queue unless it is queued already
+    pub fn push_reference(&self, reference: &str) -> Result<(), Error> {
+        let mut state = self.lock();
+        if !state.references.iter().any(|queued| queued == reference) {
+            state.references.push(reference.to_string());
+        }
+        drop(state);
+        self.save()
+    }
+
+    /// Removes `reference` from the queue once all of its items were attempted
+    pub fn finish_reference(&self, reference: &str) -> Result<(), Error> {
+        self.lock().references.retain(|queued| queued != reference);
+        self.save()
+    }
+
+    /// Deletes the saved state after a run that completed
+    pub fn clear(&self) -> Result<(), Error> {
+        match remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Path `uri` was saved at by this or an interrupted earlier run, if the file still exists
+    fn completed(&self, uri: &SpotifyUri) -> Option<PathBuf> {
+        let path = self.lock().completed.get(&uri.to_uri().ok()?).cloned()?;
+        path.exists().then_some(path)
+    }
+
+    fn complete(&self, uri: &SpotifyUri, path: &Path) -> Result<(), Error> {
+        self.lock().completed.insert(uri.to_uri()?, path.to_path_buf());
+        self.save()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, ResumeFile> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Writes the state to a temporary file renamed into place, so it is never left partial
+    fn save(&self) -> Result<(), Error> {
+        let json = serde_json::to_vec(&*self.lock()).map_err(Error::internal)?;
+        let partpath = partial_path(&self.path);
+        write(&partpath, json)?;
+        rename(&partpath, &self.path)?;
+        Ok(())
+    }
+}
+
+/// SQLite database of downloaded tracks and episodes, so they are skipped even after their
+/// files were moved or renamed
+#[cfg(feature = "sqlite")]
+pub struct TrackIndex {
+    connection: std::sync::Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl TrackIndex {
+    /// Opens the index at `path`, creating it when missing
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let connection = rusqlite::Connection::open(path).map_err(Error::internal)?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS downloads (
+                    id TEXT PRIMARY KEY,
+                    isrc TEXT,
+                    format TEXT NOT NULL,
+                    path TEXT NOT NULL,
+                    downloaded_at INTEGER NOT NULL
+                )"
+            )
+            .map_err(Error::internal)?;
+        Ok(Self { connection: std::sync::Mutex::new(connection) })
+    }
+
+    /// Path `uri` was last saved at
+    fn path(&self, uri: &SpotifyUri) -> Result<Option<PathBuf>, Error> {
+        use rusqlite::OptionalExtension;
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        let path: Option<String> = connection
+            .query_row("SELECT path FROM downloads WHERE id = ?1", [uri.to_uri()?], |row| row.get(0))
+            .optional()
+            .map_err(Error::internal)?;
+        Ok(path.map(PathBuf::from))
+    }
+
+    /// Records that `uri` was saved as `file`, replacing an earlier record
+    fn record(&self, uri: &SpotifyUri, isrc: Option<&str>, file: &SavedFile) -> Result<(), Error> {
+        let downloaded_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let connection = self.connection.lock().unwrap_or_else(|e| e.into_inner());
+        connection
+            .execute(
+                "INSERT INTO downloads (id, isrc, format, path, downloaded_at) VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (id) DO UPDATE SET
+                    isrc = excluded.isrc,
+                    format = excluded.format,
+                    path = excluded.path,
+                    downloaded_at = excluded.downloaded_at",
+                rusqlite::params![
+                    uri.to_uri()?,
+                    isrc,
+                    format!("{:?}", file.format),
+                    file.path.to_string_lossy(),
+                    downloaded_at
+                ]
+            )
+            .map_err(Error::internal)?;
+        Ok(())
+    }
+}
+
+/// Converts downloaded tracks with `ffmpeg` once they are saved and tagged
+#[cfg(feature = "transcode")]
+#[derive(Clone, Debug)]
+pub struct Transcode {
+    pub format: TranscodeFormat,
+    /// Audio bitrate passed to ffmpeg, e.g. `320k`
+    pub bitrate: String,
+    /// Whether the original file is removed after a successful conversion
+    pub replace: bool,
+}
+
+#[cfg(feature = "transcode")]
+impl Transcode {
+    /// Whether `ffmpeg` can be started from `PATH`
+    pub fn ffmpeg_available() -> bool {
+        std::process::Command::new("ffmpeg")
+            .arg("-version")
+            .output()
+            .is_ok_and(|output| output.status.success())
+    }
+
+    /// Converts `filepath` next to itself and writes `tag`, including the cover, to the result.
+    /// ffmpeg only converts the audio stream, as it can't carry pictures into every container.
+    async fn run(&self, filepath: &Path, mut tag: Tag) -> Result<(), Error> {
+        let outpath = filepath.with_extension(self.format.extension());
+        if outpath == filepath {
+            debug!("{:?} is already {:?}, not transcoding", filepath, self.format);
+            return Ok(());
+        }
+        let output = tokio::process::Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-y", "-i"])
+            .arg(filepath)
+            .args(["-vn", "-map_metadata", "-1", "-c:a", self.format.codec(), "-b:a", &self.bitrate])
+            .arg(&outpath)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(Error::internal(format!(
+                "ffmpeg failed to transcode {:?}: {}",
+                filepath,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        tag.re_map(self.format.tag_type());
+        save_tag(&tag, &outpath);
+        info!("Transcoded {:?} to {:?}", filepath, outpath);
+        if self.replace {
+            std::fs::remove_file(filepath)?;
+        }
+        Ok(())
+    }
+}
+
+/// A track of a saved file as read back from its tags, for the chapters of
+/// `DownloaderConfig::concat` and the tracks of `DownloaderConfig::cue`
+struct Chapter {
+    title: String,
+    performer: String,
+    duration: Duration,
+}
+
+impl Chapter {
+    fn read(tagged_file: &lofty::file::TaggedFile, path: &Path) -> Self {
+        let tag = tagged_file.primary_tag();
+        let text = |key: ItemKey| tag.and_then(|tag| tag.get_string(&key)).map(str::to_string);
+        Chapter {
+            title: text(ItemKey::TrackTitle)
+                .unwrap_or_else(|| path.file_stem().unwrap_or_default().to_string_lossy().into_owned()),
+            performer: text(ItemKey::TrackArtist).unwrap_or_default(),
+            // Named in full since librespot's `AudioFile` shadows the trait of the lofty prelude
+            duration: lofty::file::AudioFile::properties(tagged_file).duration(),
+        }
+    }
+}
+
+/// Files of `outcomes` downloaded by this run or before, in order
+fn saved_paths(outcomes: &[(SpotifyUri, TrackOutcome)]) -> Vec<&Path> {
+    outcomes
+        .iter()
+        .filter_map(|(_, outcome)| match outcome {
+            TrackOutcome::Downloaded(saved) => Some(saved.path.as_path()),
+            TrackOutcome::SkippedExisting(path) => Some(path.as_path()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Writes a CUE sheet titled `title` by `performer` to `cuepath`, listing each of `files`
+/// relative to the sheet with the tracks it holds back to back
+fn write_cue(cuepath: &Path, title: &str, performer: &str, files: &[(&Path, Vec<Chapter>)]) {
+    // CUE sheets have no escapes, so double quotes can't be kept inside quoted values
+    fn quoted(value: &str) -> String {
+        format!("\"{}\"", value.replace('"', "'"))
+    }
+    let dirpath = cuepath.parent().unwrap_or(Path::new(""));
+    let mut cue = String::new();
+    if !performer.is_empty() {
+        cue.push_str(&format!("PERFORMER {}\n", quoted(performer)));
+    }
+    cue.push_str(&format!("TITLE {}\n", quoted(title)));
+    let mut number = 0;
+    for (path, chapters) in files {
+        let file_type = if path.extension().is_some_and(|extension| extension == "mp3") { "MP3" } else { "WAVE" };
+        let path = path.strip_prefix(dirpath).unwrap_or(path);
+        cue.push_str(&format!("FILE {} {file_type}\n", quoted(&path.to_string_lossy())));
+        let mut start = Duration::ZERO;
+        for chapter in chapters {
+            number += 1;
+            // Offsets count minutes, seconds and frames of 1/75 second
+            let frames = start.as_millis() * 75 / 1000;
+            let (minutes, seconds, frames) = (frames / 75 / 60, frames / 75 % 60, frames % 75);
+            cue.push_str(&format!("  TRACK {number:02} AUDIO\n    TITLE {}\n", quoted(&chapter.title)));
+            if !chapter.performer.is_empty() {
+                cue.push_str(&format!("    PERFORMER {}\n", quoted(&chapter.performer)));
+            }
+            cue.push_str(&format!("    INDEX 01 {minutes:02}:{seconds:02}:{frames:02}\n"));
+            start += chapter.duration;
+        }
+    }
+    match write(cuepath, cue) {
+        Ok(()) => info!("CUE sheet saved to {:?}", cuepath),
+        Err(e) => warn!("Unable to write CUE sheet to {:?}: {}", cuepath, e),
+    }
+}
+
+/// Writes `<dirpath>/<name>.cue` listing the files of `outcomes` with one track each
+fn write_tracks_cue(dirpath: &Path, name: &str, performer: &str, outcomes: &[(SpotifyUri, TrackOutcome)]) {
+    let mut files = Vec::new();
+    for path in saved_paths(outcomes) {
+        match lofty::read_from_path(path) {
+            Ok(tagged_file) => files.push((path, vec![Chapter::read(&tagged_file, path)])),
+            Err(e) => warn!("Unable to read {:?} for the CUE sheet, leaving it out: {e}", path),
+        }
+    }
+    write_cue(&dirpath.join(sanitize_filename(name, "cue")), name, performer, &files);
+}
+
+/// Escapes `value` for ffmpeg's metadata file format, which reserves `=`, `;`, `#`, `\` and
+/// line breaks
+#[cfg(feature = "transcode")]
+fn ffmetadata_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Joins the files of `outcomes` downloaded by this run or before into `<dirpath>/<name>.<ext>`
+/// in their order with ffmpeg, copying the audio as it is and marking a chapter per track. The
+/// files have to share their format, sample rate, bit depth and channels. M4A files are joined
+/// into an `.m4b` audiobook, which players show the chapters of. Returns the joined file with
+/// its chapters, `None` when no file was saved.
+#[cfg(feature = "transcode")]
+async fn concat_files(
+    dirpath: &Path,
+    name: &str,
+    outcomes: &[(SpotifyUri, TrackOutcome)]
+) -> Result<Option<(PathBuf, Vec<Chapter>)>, Error> {
+    let paths = saved_paths(outcomes);
+    let Some(first) = paths.first() else {
+        return Ok(None);
+    };
+    let extension = first.extension().unwrap_or_default().to_string_lossy().into_owned();
+    let (muxer, joined_extension) = match extension.as_str() {
+        "flac" => ("flac", "flac"),
+        "ogg" => ("ogg", "ogg"),
+        "mp3" => ("mp3", "mp3"),
+        "m4a" => ("ipod", "m4b"),
+        _ => return Err(Error::unimplemented(format!("unable to join {extension} files"))),
+    };
+
+    let mut chapters = Vec::new();
+    let mut stream_format = None;
+    for path in &paths {
+        let tagged_file = lofty::read_from_path(path).map_err(Error::internal)?;
+        let properties = lofty::file::AudioFile::properties(&tagged_file);
+        let format = (path.extension(), properties.sample_rate(), properties.bit_depth(), properties.channels());
+        if *stream_format.get_or_insert(format) != format {
+            return Err(Error::failed_precondition(format!(
+                "{:?} has another format than {:?}, mixed formats can't be joined",
+                path, first
+            )));
+        }
+        chapters.push(Chapter::read(&tagged_file, path));
+    }
+    if paths.len() < outcomes.len() {
+        let missing = outcomes.len() - paths.len();
+        warn!("{missing} of {} tracks weren't saved and are missing from {name}", outcomes.len());
+    }
+
+    let mut list = String::new();
+    for path in &paths {
+        let path = std::path::absolute(path)?;
+        list.push_str(&format!("file '{}'\n", path.to_string_lossy().replace('\'', "'\\''")));
+    }
+    let mut metadata = format!(";FFMETADATA1\ntitle={}\n", ffmetadata_escape(name));
+    let mut start = Duration::ZERO;
+    for chapter in &chapters {
+        let end = start + chapter.duration;
+        let title = match chapter.performer.as_str() {
+            "" => chapter.title.clone(),
+            performer => format!("{performer} - {}", chapter.title),
+        };
+        metadata.push_str(&format!(
+            "[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            start.as_millis(),
+            end.as_millis(),
+            ffmetadata_escape(&title)
+        ));
+        start = end;
+    }
+
+    let outpath = dirpath.join(sanitize_filename(name, joined_extension));
+    let partpath = partial_path(&outpath);
+    let listpath = outpath.with_extension("concat.txt");
+    let metadatapath = outpath.with_extension("chapters.txt");
+    let joined = async {
+        write(&listpath, list)?;
+        write(&metadatapath, metadata)?;
+        let output = tokio::process::Command::new("ffmpeg")
+            .args(["-hide_banner", "-loglevel", "error", "-y", "-f", "concat", "-safe", "0", "-i"])
+            .arg(&listpath)
+            .arg("-i")
+            .arg(&metadatapath)
+            .args(["-map", "0:a", "-map_metadata", "1", "-map_chapters", "1", "-c", "copy", "-f", muxer])
+            .arg(&partpath)
+            .output()
+            .await?;
+        if !output.status.success() {
+            return Err(Error::internal(format!(
+                "ffmpeg failed to join {} files: {}",
+                paths.len(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        rename(&partpath, &outpath)?;
+        Ok(())
+    };
+    let joined = joined.await;
+    _ = remove_file(&listpath);
+    _ = remove_file(&metadatapath);
+    if joined.is_err() {
+        _ = remove_file(&partpath);
+    }
+    joined.map(|()| Some((outpath, chapters)))
+}
+
+/// One record of the `--manifest` file
+#[derive(Serialize)]
+struct ManifestEntry {
+    id: String,
+    outcome: &'static str,
+    format: Option<String>,
+    path: Option<PathBuf>,
+    size: Option<u64>,
+    tagged: Option<bool>,
+    on_existing: Option<OnExisting>,
+    error: Option<String>,
+}
+
+impl ManifestEntry {
+    fn new(uri: &SpotifyUri, outcome: &TrackOutcome) -> Self {
+        let mut entry = ManifestEntry {
+            id: uri.to_uri().unwrap_or_else(|_| uri.to_string()),
+            outcome: "",
+            format: None,
+            path: None,
+            size: None,
+            tagged: None,
+            on_existing: None,
+            error: None,
+        };
+        match outcome {
+            TrackOutcome::Downloaded(saved) => {
+                entry.outcome = "downloaded";
+                entry.format = Some(format!("{:?}", saved.format));
+                entry.path = Some(saved.path.clone());
+                entry.tagged = Some(saved.tagged);
+                entry.on_existing = saved.on_existing;
+            }
+            TrackOutcome::SkippedExisting(path) => {
+                entry.outcome = "skipped";
+                entry.path = Some(path.clone());
+            }
+            TrackOutcome::SkippedUnsupportedFormat | TrackOutcome::SkippedNotDownloaded => {
+                entry.outcome = "skipped";
+            }
+            TrackOutcome::SkippedDuplicate(_) => entry.outcome = "duplicate",
+            TrackOutcome::SkippedBelowMinQuality(format) => {
+                entry.outcome = "skipped";
+                entry.format = Some(format!("{format:?}"));
+            }
+            TrackOutcome::Cancelled => entry.outcome = "cancelled",
+            TrackOutcome::Retagged(path) => {
+                entry.outcome = "retagged";
+                entry.path = Some(path.clone());
+            }
+            TrackOutcome::DryRun { file, exists } => {
+                entry.outcome = if *exists { "skipped" } else { "planned" };
+                entry.format = Some(format!("{:?}", file.format));
+                entry.path = Some(file.path.clone());
+                entry.on_existing = file.on_existing;
+            }
+            TrackOutcome::Failed(e) => {
+                entry.outcome = "failed";
+                entry.error = Some(e.to_string());
+            }
+        }
+        entry.size = entry.path.as_ref().and_then(|path| path.metadata().ok()).map(|metadata| metadata.len());
+        entry
+    }
+}
+
+/// Writes a JSON array describing every outcome of the run to `path`. The manifest is written
+/// to a temporary file first and renamed into place, so it is either complete or missing.
+pub fn write_manifest(path: &Path, outcomes: &[(SpotifyUri, TrackOutcome)]) -> Result<(), Error> {
+    let entries: Vec<ManifestEntry> = outcomes.iter().map(|(uri, outcome)| ManifestEntry::new(uri, outcome)).collect();
+    let json = serde_json::to_vec_pretty(&entries).map_err(Error::internal)?;
+    let partpath = partial_path(path);
+    write(&partpath, json)?;
+    rename(&partpath, path)?;
+    info!("Manifest saved to {:?}", path);
+    Ok(())
+}
+
+pub type Cover = (Vec<u8>, MimeType);
+
+pub struct DownloaderConfig {
+    /// Number of tracks downloaded at the same time within an album or playlist
+    pub concurrency: usize,
+    /// Whether progress bars are drawn to stderr
+    pub show_progress: bool,
+    /// What is done when a non-empty file already exists at the path of a download
+    pub on_existing: OnExisting,
+    /// Track filename without extension, see `FILENAME_PLACEHOLDERS` for the placeholders
+    pub filename_template: String,
+    /// Destination directory evaluated per track below the output directory, see
+    /// `DIRECTORY_PLACEHOLDERS`. When unset, files are grouped by album, playlist or show.
+    pub directory_template: Option<String>,
+    /// Separator between multiple artists in the artist tag and the `{artist}` placeholder
+    pub artist_separator: String,
+    /// Which featured artists are credited along with the main artists
+    pub featured_artists: FeaturedArtists,
+    /// Separator between multiple album artists in the album artist tag
+    pub album_artist_separator: String,
+    /// Number of the primary artist's genres written as genre tags, 0 skips the lookup
+    pub genre_count: usize,
+    /// Whether lyrics are fetched and how they are stored
+    pub lyrics: LyricsMode,
+    /// Whether the lyrics text is embedded into the tags regardless of `lyrics`. This costs an
+    /// extra request per track.
+    pub embed_lyrics: bool,
+    /// Whether writer and producer credits are tagged as composer, lyricist and producer. This
+    /// costs an extra request per track.
+    pub credits: bool,
+    /// Whether the popularity of tracks and the date they were added to the downloaded playlist
+    /// or Liked Songs are tagged in custom fields, for sorting by them offline
+    pub extended_tags: bool,
+    /// Whether tracks sharing the ISRC of a track already downloaded by this run are skipped.
+    /// Artist releases are then downloaded oldest first, so the original release is kept.
+    pub dedup_isrc: bool,
+    /// Whether FLAC downloads are decoded after being written to check they are intact. A
+    /// download that isn't is downloaded once more before it fails.
+    pub verify: bool,
+    /// Whether the open.spotify.com link of each track is embedded into its tags
+    pub embed_url: bool,
+    /// Whether the cover is also saved as `cover.<ext>` next to the downloaded files
+    pub save_cover: bool,
+    /// Whether the album and track metadata of each album is saved as `album.json` into its
+    /// directory once its tracks are done
+    pub save_metadata: bool,
+    /// Whether Kodi and Jellyfin NFO files are written, `album.nfo` into each album directory
+    /// and `<track>.nfo` next to each track
+    pub nfo: bool,
+    /// Preferred cover size, falling back to the next larger and then the largest available
+    pub cover_size: image::ImageSize,
+    /// Largest width and height of embedded covers. When set, embedded covers are re-encoded
+    /// as JPEG with `cover_quality` and scaled down when larger, saved covers stay original.
+    pub cover_max_size: Option<u32>,
+    /// JPEG quality from 1 to 100 of covers re-encoded for `cover_max_size` or converted from
+    /// formats other than JPEG and PNG
+    pub cover_quality: u8,
+    /// Formats to download, most preferred first. Tracks in none of them are skipped.
+    pub format_preference: Vec<AudioFileFormat>,
+    /// Lowest acceptable format by its rank in `FORMAT_PREFERENCE`. Tracks only available in
+    /// worse formats are downloaded with a warning, or skipped with `strict_quality`.
+    pub min_quality: Option<AudioFileFormat>,
+    /// Whether tracks below `min_quality` are skipped instead of downloaded
+    pub strict_quality: bool,
+    /// Whether every track is flagged as a compilation track instead of only those on albums
+    /// detected as compilations
+    pub force_compilation: bool,
+    /// Whether an `.m3u8` playlist is written for albums, shows and Liked Songs too, playlists
+    /// always get one
+    pub album_playlist: bool,
+    /// Whether a `<album>.cue` sheet listing the downloaded tracks is written into each album
+    /// directory, and a `<playlist>.cue` sheet with the track offsets next to each file joined
+    /// with `concat`
+    pub cue: bool,
+    /// Whether playlist tracks are tagged with the playlist as album and their position in it
+    /// as track number, so players sorting by track number keep the playlist order
+    pub playlist_as_album: bool,
+    /// Types of release downloaded of an artist
+    pub release_types: Vec<ReleaseType>,
+    /// Whether items are only resolved and reported as `TrackOutcome::DryRun`, without
+    /// downloading audio or writing anything to disk
+    pub dry_run: bool,
+    /// Whether the tags of existing downloads are rewritten from fresh metadata instead of
+    /// downloading audio. Items without an existing file are reported as
+    /// `TrackOutcome::SkippedNotDownloaded`.
+    pub retag: bool,
+    /// Like `retag`, but only the cover of existing downloads without an embedded one is
+    /// added, leaving their other tags alone
+    pub cover_only: bool,
+    /// Whether collections are refused when their estimated size exceeds the free disk space.
+    /// The estimate loads the metadata of every item up front.
+    pub space_check: bool,
+    /// Conversion applied to every downloaded track
+    #[cfg(feature = "transcode")]
+    pub transcode: Option<Transcode>,
+    /// Whether the tracks of playlists are also joined into one `<playlist>.<ext>` file with a
+    /// chapter per track, for continuous mixes, or `<playlist>.m4b` for M4A tracks. Tracks saved
+    /// in different formats aren't joined.
+    #[cfg(feature = "transcode")]
+    pub concat: bool,
+    /// Uncompressed format lossless downloads are also decoded to. Lossy downloads are left as
+    /// they are.
+    #[cfg(feature = "pcm")]
+    pub pcm: Option<PcmFormat>,
+    /// Maximum number of attempts for network requests, including the first one
+    pub retry_attempts: u32,
+    /// Delay before the first retry, doubled on every further attempt
+    pub retry_base_delay: Duration,
+    /// Time a single attempt of a network request like loading a cover or an audio key may take
+    pub request_timeout: Duration,
+    /// Time the download of a single track or episode may take, unlimited when unset. Slower
+    /// downloads fail with `DownloadError::TimedOut`, leaving no partial file behind.
+    pub track_timeout: Option<Duration>,
+    /// Country code whose catalog restrictions tracks have to pass, the account's country when
+    /// unset. Spotify still decides the country of the account itself, so this only selects
+    /// which version of a track is downloaded.
+    pub market: Option<String>,
+    /// Further country codes tried in order when a track isn't available in `market`
+    pub fallback_markets: Vec<String>,
+    /// Maximum number of times dropped sessions are reconnected during the lifetime of the
+    /// downloader
+    pub max_reconnects: u32,
+    /// Combined throughput of all downloads in bytes per second, unlimited when unset
+    pub max_rate: Option<u64>,
+    /// How audio files are fetched from the CDN
+    pub prefetch: Prefetch,
+    /// Extra headers sent with cover requests, replacing the default `Accept` and the
+    /// browser-like `User-Agent` when given
+    pub cover_headers: Vec<(HeaderName, HeaderValue)>,
+    /// Version downloaded of tracks that have both an explicit and a clean one. Tracks with
+    /// only one version are downloaded as they are.
+    pub prefer: Option<Variant>,
+    /// Index of earlier downloads, consulted with `OnExisting::Skip` before the file system
+    #[cfg(feature = "sqlite")]
+    pub index: Option<TrackIndex>,
+    /// Directory covers are kept in by id, so later runs don't fetch them again. Covers are
+    /// only kept in memory when unset.
+    pub cover_cache: Option<PathBuf>,
+    /// Progress of the run, recording every saved item and skipping those an interrupted
+    /// earlier run saved
+    pub resume: Option<Arc<ResumeState>>,
+}
+
+impl DownloaderConfig {
+    pub fn builder() -> DownloaderConfigBuilder {
+        DownloaderConfigBuilder::default()
+    }
+
+    /// Whether existing downloads are updated instead of downloading anything
+    fn updates_existing(&self) -> bool {
+        self.retag || self.cover_only
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        parse_template(&self.filename_template, &FILENAME_PLACEHOLDERS)?;
+        if self.format_preference.is_empty() {
+            return Err(Error::invalid_argument("format preference is empty"));
+        }
+        if let Some(directory_template) = &self.directory_template {
+            parse_template(directory_template, &DIRECTORY_PLACEHOLDERS)?;
+        }
+        for market in self.market.iter().chain(&self.fallback_markets) {
+            if market.len() != 2 || !market.bytes().all(|b| b.is_ascii_uppercase()) {
+                return Err(Error::invalid_argument(format!("{market:?} is not a two-letter country code")));
+            }
+        }
+        if self.cover_max_size == Some(0) || !(1..=100).contains(&self.cover_quality) {
+            return Err(Error::invalid_argument("cover size must be positive and quality between 1 and 100"));
+        }
+        if self.max_rate == Some(0) {
+            return Err(Error::invalid_argument("max rate must be positive"));
+        }
+        if self.request_timeout.is_zero() || self.track_timeout.is_some_and(|timeout| timeout.is_zero()) {
+            return Err(Error::invalid_argument("timeouts must be positive"));
+        }
+        if self.retag && self.dry_run {
+            return Err(Error::invalid_argument("retag can't be combined with a dry run"));
+        }
+        if self.cover_only && (self.retag || self.dry_run) {
+            return Err(Error::invalid_argument("cover only can't be combined with retag or a dry run"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for DownloaderConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: DEFAULT_CONCURRENCY,
+            show_progress: true,
+            on_existing: OnExisting::Skip,
+            filename_template: DEFAULT_FILENAME_TEMPLATE.to_string(),
+            directory_template: None,
+            artist_separator: DEFAULT_ARTIST_SEPARATOR.to_string(),
+            featured_artists: FeaturedArtists::Keep,
+            album_artist_separator: DEFAULT_ALBUM_ARTIST_SEPARATOR.to_string(),
+            genre_count: 1,
+            lyrics: LyricsMode::None,
+            embed_lyrics: false,
+            credits: false,
+            extended_tags: false,
+            dedup_isrc: false,
+            verify: false,
+            embed_url: true,
+            save_cover: false,
+            save_metadata: false,
+            nfo: false,
+            cover_size: image::ImageSize::XLARGE,
+            cover_max_size: None,
+            cover_quality: DEFAULT_COVER_QUALITY,
+            format_preference: FORMAT_PREFERENCE.to_vec(),
+            min_quality: None,
+            strict_quality: false,
+            force_compilation: false,
+            album_playlist: false,
+            cue: false,
+            playlist_as_album: false,
+            release_types: DEFAULT_RELEASE_TYPES.to_vec(),
+            dry_run: false,
+            retag: false,
+            cover_only: false,
+            space_check: true,
+            #[cfg(feature = "transcode")]
+            transcode: None,
+            #[cfg(feature = "transcode")]
+            concat: false,
+            #[cfg(feature = "pcm")]
+            pcm: None,
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            retry_base_delay: DEFAULT_RETRY_BASE_DELAY,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            track_timeout: None,
+            max_reconnects: 5,
+            market: None,
+            fallback_markets: Vec::new(),
+            max_rate: None,
+            prefetch: Prefetch::Whole,
+            cover_headers: Vec::new(),
+            prefer: None,
+            #[cfg(feature = "sqlite")]
+            index: None,
+            cover_cache: None,
+            resume: None,
+        }
+    }
+}
+
+/// Builds a `DownloaderConfig` from the defaults, each setter sets the field of the same
+/// name. `build` validates the result.
+#[derive(Default)]
+pub struct DownloaderConfigBuilder {
+    config: DownloaderConfig,
+}
+
+impl DownloaderConfigBuilder {
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.config.concurrency = concurrency;
+        self
+    }
+
+    pub fn show_progress(mut self, show_progress: bool) -> Self {
+        self.config.show_progress = show_progress;
+        self
+    }
+
+    pub fn on_existing(mut self, on_existing: OnExisting) -> Self {
+        self.config.on_existing = on_existing;
+        self
+    }
+
+    pub fn filename_template(mut self, filename_template: impl Into<String>) -> Self {
+        self.config.filename_template = filename_template.into();
+        self
+    }
+
+    pub fn directory_template(mut self, directory_template: impl Into<String>) -> Self {
+        self.config.directory_template = Some(directory_template.into());
+        self
+    }
+
+    pub fn artist_separator(mut self, artist_separator: impl Into<String>) -> Self {
+        self.config.artist_separator = artist_separator.into();
+        self
+    }
+
+    pub fn featured_artists(mut self, featured_artists: FeaturedArtists) -> Self {
+        self.config.featured_artists = featured_artists;
+        self
+    }
+
+    pub fn album_artist_separator(mut self, album_artist_separator: impl Into<String>) -> Self {
+        self.config.album_artist_separator = album_artist_separator.into();
+        self
+    }
+
+    pub fn genre_count(mut self, genre_count: usize) -> Self {
+        self.config.genre_count = genre_count;
+        self
+    }
+
+    pub fn lyrics(mut self, lyrics: LyricsMode) -> Self {
+        self.config.lyrics = lyrics;
+        self
+    }
+
+    pub fn embed_lyrics(mut self, embed_lyrics: bool) -> Self {
+        self.config.embed_lyrics = embed_lyrics;
+        self
+    }
+
+    pub fn credits(mut self, credits: bool) -> Self {
+        self.config.credits = credits;
+        self
+    }
+
+    pub fn extended_tags(mut self, extended_tags: bool) -> Self {
+        self.config.extended_tags = extended_tags;
+        self
+    }
+
+    pub fn dedup_isrc(mut self, dedup_isrc: bool) -> Self {
+        self.config.dedup_isrc = dedup_isrc;
+        self
+    }
+
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.config.verify = verify;
+        self
+    }
+
+    pub fn embed_url(mut self, embed_url: bool) -> Self {
+        self.config.embed_url = embed_url;
+        self
+    }
+
+    pub fn save_cover(mut self, save_cover: bool) -> Self {
+        self.config.save_cover = save_cover;
+        self
+    }
+
+    pub fn save_metadata(mut self, save_metadata: bool) -> Self {
+        self.config.save_metadata = save_metadata;
+        self
+    }
+
+    pub fn nfo(mut self, nfo: bool) -> Self {
+        self.config.nfo = nfo;
+        self
+    }
+
+    pub fn cover_size(mut self, cover_size: image::ImageSize) -> Self {
+        self.config.cover_size = cover_size;
+        self
+    }
+
+    pub fn cover_max_size(mut self, cover_max_size: u32) -> Self {
+        self.config.cover_max_size = Some(cover_max_size);
+        self
+    }
+
+    pub fn cover_quality(mut self, cover_quality: u8) -> Self {
+        self.config.cover_quality = cover_quality;
+        self
+    }
+
+    pub fn format_preference(mut self, format_preference: Vec<AudioFileFormat>) -> Self {
+        self.config.format_preference = format_preference;
+        self
+    }
+
+    pub fn min_quality(mut self, min_quality: AudioFileFormat) -> Self {
+        self.config.min_quality = Some(min_quality);
+        self
+    }
+
+    pub fn strict_quality(mut self, strict_quality: bool) -> Self {
+        self.config.strict_quality = strict_quality;
+        self
+    }
+
+    pub fn force_compilation(mut self, force_compilation: bool) -> Self {
+        self.config.force_compilation = force_compilation;
+        self
+    }
+
+    pub fn album_playlist(mut self, album_playlist: bool) -> Self {
+        self.config.album_playlist = album_playlist;
+        self
+    }
+
+    pub fn cue(mut self, cue: bool) -> Self {
+        self.config.cue = cue;
+        self
+    }
+
+    pub fn playlist_as_album(mut self, playlist_as_album: bool) -> Self {
+        self.config.playlist_as_album = playlist_as_album;
+        self
+    }
+
+    pub fn release_types(mut self, release_types: Vec<ReleaseType>) -> Self {
+        self.config.release_types = release_types;
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.config.dry_run = dry_run;
+        self
+    }
+
+    pub fn retag(mut self, retag: bool) -> Self {
+        self.config.retag = retag;
+        self
+    }
+
+    pub fn cover_only(mut self, cover_only: bool) -> Self {
+        self.config.cover_only = cover_only;
+        self
+    }
+
+    pub fn space_check(mut self, space_check: bool) -> Self {
+        self.config.space_check = space_check;
+        self
+    }
+
+    #[cfg(feature = "transcode")]
+    pub fn transcode(mut self, transcode: Transcode) -> Self {
+        self.config.transcode = Some(transcode);
+        self
+    }
+
+    #[cfg(feature = "transcode")]
+    pub fn concat(mut self, concat: bool) -> Self {
+        self.config.concat = concat;
+        self
+    }
+
+    #[cfg(feature = "pcm")]
+    pub fn pcm(mut self, pcm: PcmFormat) -> Self {
+        self.config.pcm = Some(pcm);
+        self
+    }
+
+    pub fn retry_attempts(mut self, retry_attempts: u32) -> Self {
+        self.config.retry_attempts = retry_attempts;
+        self
+    }
+
+    pub fn retry_base_delay(mut self, retry_base_delay: Duration) -> Self {
+        self.config.retry_base_delay = retry_base_delay;
+        self
+    }
+
+    pub fn request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.config.request_timeout = request_timeout;
+        self
+    }
+
+    pub fn track_timeout(mut self, track_timeout: Duration) -> Self {
+        self.config.track_timeout = Some(track_timeout);
+        self
+    }
+
+    pub fn market(mut self, market: impl Into<String>) -> Self {
+        self.config.market = Some(market.into());
+        self
+    }
+
+    pub fn fallback_markets(mut self, fallback_markets: Vec<String>) -> Self {
+        self.config.fallback_markets = fallback_markets;
+        self
+    }
+
+    pub fn max_reconnects(mut self, max_reconnects: u32) -> Self {
+        self.config.max_reconnects = max_reconnects;
+        self
+    }
+
+    pub fn max_rate(mut self, max_rate: u64) -> Self {
+        self.config.max_rate = Some(max_rate);
+        self
+    }
+
+    pub fn prefetch(mut self, prefetch: Prefetch) -> Self {
+        self.config.prefetch = prefetch;
+        self
+    }
+
+    pub fn cover_headers(mut self, cover_headers: Vec<(HeaderName, HeaderValue)>) -> Self {
+        self.config.cover_headers = cover_headers;
+        self
+    }
+
+    pub fn prefer(mut self, prefer: Variant) -> Self {
+        self.config.prefer = Some(prefer);
+        self
+    }
+
+    #[cfg(feature = "sqlite")]
+    pub fn index(mut self, index: TrackIndex) -> Self {
+        self.config.index = Some(index);
+        self
+    }
+
+    pub fn cover_cache(mut self, cover_cache: PathBuf) -> Self {
+        self.config.cover_cache = Some(cover_cache);
+        self
+    }
+
+    pub fn resume(mut self, resume: Arc<ResumeState>) -> Self {
+        self.config.resume = Some(resume);
+        self
+    }
+
+    pub fn build(self) -> Result<DownloaderConfig, Error> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+type CoverClient = Client<ProxyConnector<HttpsConnector<HttpConnector>>, Full<Bytes>>;
+
+pub struct Downloader {
+    pub config: DownloaderConfig,
+    /// Session metadata is requested through, replaced when it is reconnected
+    session: RwLock<Session>,
+    /// Sessions audio is downloaded with, taking turns per track to spread out rate limits
+    audio_sessions: RwLock<Vec<Session>>,
+    next_audio_session: AtomicUsize,
+    /// Number of reconnects so far, also telling downloads whether sessions were replaced
+    /// since they started
+    reconnects: AtomicU32,
+    reconnect_lock: Mutex<()>,
+    /// Covers by id. Concurrent downloads of tracks sharing a cover wait for the same fetch.
+    album_cover_cache: Arc<Mutex<HashMap<String, Arc<OnceCell<Cover>>>>>,
+    artist_genre_cache: Arc<Mutex<HashMap<SpotifyUri, Vec<String>>>>,
+    track_credits_cache: Arc<Mutex<HashMap<SpotifyUri, Credits>>>,
+    /// Directories `cover.<ext>` was saved into by this run, so each gets written once even
+    /// when several albums share a directory
+    cover_dirs: Mutex<HashSet<PathBuf>>,
+    /// Tracks downloaded by this run by ISRC, see `DownloaderConfig::dedup_isrc`
+    isrcs: Mutex<HashMap<String, SpotifyUri>>,
+    /// When tracks were added to the playlist or Liked Songs they were last resolved from, see
+    /// `DownloaderConfig::extended_tags`
+    added: Mutex<HashMap<SpotifyUri, String>>,
+    progress: MultiProgress,
+    events: Option<mpsc::UnboundedSender<DownloadEvent>>,
+    cancel: CancellationToken,
+    throttle: Arc<Throttle>,
+    /// Client covers are fetched with, built on first use. librespot's HTTP client would
+    /// replace the `User-Agent` of cover requests with its own.
+    cover_client: OnceLock<CoverClient>,
+}
+
+impl Downloader {
+    pub fn new(session: Session) -> Self {
+        Self {
+            audio_sessions: RwLock::new(vec![session.clone()]),
+            next_audio_session: AtomicUsize::new(0),
+            reconnects: AtomicU32::new(0),
+            reconnect_lock: Mutex::new(()),
+            session: RwLock::new(session),
+            config: DownloaderConfig::default(),
+            album_cover_cache: Arc::new(Mutex::new(HashMap::new())),
+            artist_genre_cache: Arc::new(Mutex::new(HashMap::new())),
+            track_credits_cache: Arc::new(Mutex::new(HashMap::new())),
+            cover_dirs: Mutex::new(HashSet::new()),
+            isrcs: Mutex::new(HashMap::new()),
+            added: Mutex::new(HashMap::new()),
+            progress: MultiProgress::new(),
+            events: None,
+            cancel: CancellationToken::new(),
+            throttle: Arc::new(Throttle::new()),
+            cover_client: OnceLock::new(),
+        }
+    }
+
+    /// Downloader using `config` instead of the defaults `new` starts with
+    pub fn with_config(session: Session, config: DownloaderConfig) -> Self {
+        Self { config, ..Self::new(session) }
+    }
+
+    /// Adds the session of another account to download audio with. Metadata is always
+    /// requested through the session passed to `new`.
+    pub fn add_session(&mut self, session: Session) {
+        self.audio_sessions.write().unwrap_or_else(|e| e.into_inner()).push(session);
+    }
+
+    /// Session metadata is requested through
+    pub fn session(&self) -> Session {
+        self.session.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Starts reporting the progress of every following download to the returned receiver,
+    /// replacing any earlier one. The download methods still return all outcomes when done.
+    pub fn events(&mut self) -> mpsc::UnboundedReceiver<DownloadEvent> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.events = Some(sender);
+        receiver
+    }
+
+    /// Stops the downloads once `token` is cancelled. Tracks not started yet are reported as
+    /// `TrackOutcome::Cancelled`, running ones stop within the current read and remove their
+    /// partial file.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancel = token;
+    }
+
+    fn emit(&self, event: DownloadEvent) {
+        if let Some(events) = &self.events {
+            _ = events.send(event);
+        }
+    }
+
+    fn audio_session(&self) -> Session {
+        let index = self.next_audio_session.fetch_add(1, Ordering::Relaxed);
+        let audio_sessions = self.audio_sessions.read().unwrap_or_else(|e| e.into_inner());
+        audio_sessions[index % audio_sessions.len()].clone()
+    }
+
+    /// Replaces every disconnected session with a new one logged in with the credentials in
+    /// its cache. Returns whether sessions were replaced since the reconnect count was
+    /// `seen`, in which case a failed download is worth another attempt.
+    async fn reconnect_dropped(&self, seen: u32) -> Result<bool, DownloadError> {
+        let _guard = self.reconnect_lock.lock().await;
+        if self.reconnects.load(Ordering::Relaxed) != seen {
+            return Ok(true);
+        }
+        let session = self.session();
+        let audio_sessions = self.audio_sessions.read().unwrap_or_else(|e| e.into_inner()).clone();
+        if !session.is_invalid() && !audio_sessions.iter().any(Session::is_invalid) {
+            return Ok(false);
+        }
+        let attempt = seen + 1;
+        if attempt > self.config.max_reconnects {
+            let e = Error::unavailable(format!("session dropped, gave up after {seen} reconnects"));
+            return Err(DownloadError::Session(e));
+        }
+        self.reconnects.store(attempt, Ordering::Relaxed);
+        warn!("Session dropped, reconnecting (attempt {attempt}/{})", self.config.max_reconnects);
+
+        let mut replaced: Vec<Session> = Vec::new();
+        if session.is_invalid() {
+            let new = reconnect(&session).await?;
+            *self.session.write().unwrap_or_else(|e| e.into_inner()) = new.clone();
+            replaced.push(new);
+        }
+        let mut reconnected = Vec::with_capacity(audio_sessions.len());
+        for audio_session in audio_sessions {
+            if !audio_session.is_invalid() {
+                reconnected.push(audio_session);
+                continue;
+            }
+            // Accounts share one session for metadata and audio, so reuse a reconnected one
+            let existing = replaced.iter().find(|new| new.username() == audio_session.username());
+            let new = match existing {
+                Some(new) => new.clone(),
+                None => reconnect(&audio_session).await?,
+            };
+            replaced.push(new.clone());
+            reconnected.push(new);
+        }
+        *self.audio_sessions.write().unwrap_or_else(|e| e.into_inner()) = reconnected;
+        info!("Reconnected {} sessions", replaced.len());
+        Ok(true)
+    }
+
+    pub async fn download_uri(
+        &self,
+        uri: &SpotifyUri,
+        directory: &str
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        match uri {
+            SpotifyUri::Album { .. } => {
+                let album = Album::get(&self.session(), uri).await.map_err(DownloadError::Metadata)?;
+                self.download_album(album, directory).await
+            }
+            SpotifyUri::Playlist { .. } => self.download_playlist(uri, directory).await,
+            SpotifyUri::Artist { .. } => {
+                let artist = Artist::get(&self.session(), uri).await.map_err(DownloadError::Metadata)?;
+                self.download_artist(artist, directory, &self.config.release_types).await
+            }
+            SpotifyUri::Track { .. } => {
+                let dirpath = PathBuf::from(directory);
+                self.create_dir(&dirpath);
+                let outcome = self.download_item(uri, None, None, &dirpath).await;
+                Ok(vec![(uri.clone(), outcome)])
+            }
+            SpotifyUri::Show { .. } => {
+                let show = Show::get(&self.session(), uri).await.map_err(DownloadError::Metadata)?;
+                self.download_show(show, directory).await
+            }
+            SpotifyUri::Episode { .. } => {
+                let dirpath = PathBuf::from(directory);
+                self.create_dir(&dirpath);
+                let outcome = self.download_item(uri, None, None, &dirpath).await;
+                Ok(vec![(uri.clone(), outcome)])
+            }
+            _ => Err(DownloadError::InvalidInput(Error::unimplemented(format!(
+                "downloading {} is not supported",
+                uri.item_type()
+            )))),
+        }
+    }
+
+    pub async fn download_album_by_id(
+        &self,
+        base62: &str,
+        directory: &str
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        let id = SpotifyId::from_base62(base62).map_err(DownloadError::InvalidInput)?;
+        let uri = SpotifyUri::Album { id };
+        let album = Album::get(&self.session(), &uri).await.map_err(DownloadError::Metadata)?;
+        self.download_album(album, directory).await
+    }
+
+    pub async fn download_album(
+        &self,
+        album: Album,
+        directory: &str
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        info!("Downloading Album: {}", album.name);
+        let track_uris: Vec<SpotifyUri> = album.tracks().cloned().collect();
+        if track_uris.is_empty() {
+            warn!("<{}> {} has no tracks", album.id, album.name);
+            return Err(DownloadError::Empty(album.id.clone()));
+        }
+        let dirpath = self.collection_dir(directory, &album.name);
+        info!("<{}> saved at {:?}", album.id, dirpath);
+        self.check_space(&track_uris, &dirpath).await?;
+        self.create_dir(&dirpath);
+        let outcomes = self.download_uris(&track_uris, Some(&album), None, &dirpath).await;
+        let downloadable = self.any_downloadable(&album.id, &dirpath, &outcomes);
+        if self.config.album_playlist && downloadable && !self.config.dry_run && !self.config.updates_existing() {
+            write_playlist_file(&dirpath, &album.name, &outcomes);
+        }
+        if self.config.cue && downloadable && !self.config.dry_run && !self.config.updates_existing() {
+            let performer = join_album_artists(&album, &self.config.album_artist_separator);
+            write_tracks_cue(&dirpath, &album.name, &performer, &outcomes);
+        }
+        if self.config.save_metadata && downloadable && !self.config.dry_run && !self.config.cover_only {
+            self.save_album_metadata(&album, &track_uris, &dirpath).await;
+        }
+        if self.config.nfo && downloadable && !self.config.dry_run && !self.config.cover_only {
+            self.save_album_nfo(&album, &dirpath).await;
+        }
+        Ok(outcomes)
+    }
+
+    /// Writes the metadata of `album` and its tracks `track_uris` to `<dirpath>/album.json`.
+    /// Tracks whose metadata fails to load are left out.
+    async fn save_album_metadata(&self, album: &Album, track_uris: &[SpotifyUri], dirpath: &Path) {
+        let mut tracks = Vec::with_capacity(track_uris.len());
+        for uri in track_uris {
+            match Track::get(&self.session(), uri).await {
+                Ok(track) => tracks.push(track),
+                Err(e) => warn!("<{}> unable to load track metadata for album.json: {e}", uri),
+            }
+        }
+        let path = dirpath.join("album.json");
+        let written = serde_json::to_vec_pretty(&AlbumMetadata::new(album, &tracks))
+            .map_err(io::Error::other)
+            .and_then(|json| write(&path, json));
+        match written {
+            Ok(()) => info!("<{}> metadata saved to {:?}", album.id, path),
+            Err(e) => warn!("Unable to write metadata to {:?}: {e}", path),
+        }
+    }
+
+    /// Writes the `<album>` NFO of `album` to `<dirpath>/album.nfo`, referencing the cover
+    /// by its URL
+    async fn save_album_nfo(&self, album: &Album, dirpath: &Path) {
+        let mut fields = vec![("title", album.name.clone())];
+        fields.extend(album.artists.iter().map(|artist| ("artist", artist.name.clone())));
+        if let Some(artist) = album.artists.first() {
+            let genres = self.get_genres(&artist.id).await.into_iter().take(self.config.genre_count);
+            fields.extend(genres.map(|genre| ("genre", genre)));
+        }
+        let date = release_date(album).unwrap_or_default();
+        fields.push(("year", date.chars().take(4).collect()));
+        fields.push(("releasedate", date));
+        fields.push(("label", album.label.trim().to_string()));
+        fields.push(("type", format!("{:?}", album.album_type).to_lowercase()));
+        fields.push(("compilation", is_compilation(album).to_string()));
+        let cover = album.covers.iter().max_by_key(|cover| cover.width);
+        fields.extend(cover.map(|cover| ("thumb", format!("{IMAGE_URL}{}", cover.id))));
+        write_nfo(&dirpath.join("album.nfo"), "album", &fields);
+    }
+
+    pub async fn download_artist_by_id(
+        &self,
+        base62: &str,
+        directory: &str,
+        release_types: &[ReleaseType]
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        let id = SpotifyId::from_base62(base62).map_err(DownloadError::InvalidInput)?;
+        let uri = SpotifyUri::Artist { id };
+        let artist = Artist::get(&self.session(), &uri).await.map_err(DownloadError::Metadata)?;
+        self.download_artist(artist, directory, release_types).await
+    }
+
+    pub async fn download_artist(
+        &self,
+        artist: Artist,
+        directory: &str,
+        release_types: &[ReleaseType]
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        info!("Downloading Artist: {}", artist.name);
+        let dirpath = self.collection_dir(directory, &artist.name);
+        let dirpath = dirpath.to_string_lossy().into_owned();
+        let (mut albums, filtered) =
+            self.get_artist_albums(&artist, release_types).await.map_err(DownloadError::Metadata)?;
+        if self.config.dedup_isrc {
+            // Undated releases go last
+            albums.sort_by_key(|album| {
+                let date = &album.date;
+                (date.year() <= 0, date.year(), date.month() as u8, date.day())
+            });
+        }
+        info!("<{}> has {} matching releases, {filtered} filtered out by type", artist.id, albums.len());
+        let mut outcomes = Vec::new();
+        for album in albums {
+            match self.download_album(album, &dirpath).await {
+                Ok(downloaded) => outcomes.extend(downloaded),
+                Err(DownloadError::Empty(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(outcomes)
+    }
+
+    /// Resolves every release of `artist` whose type is in `release_types`, along with the
+    /// number of releases of other types. Releases sharing the same name are collapsed into
+    /// the variant offering the best audio format.
+    async fn get_artist_albums(
+        &self,
+        artist: &Artist,
+        release_types: &[ReleaseType]
+    ) -> Result<(Vec<Album>, usize), Error> {
+        let album_uris = artist.albums.iter()
+            .chain(artist.singles.iter())
+            .chain(artist.compilations.iter())
+            .flat_map(|group| group.iter().map(|uri| (uri, false)))
+            .chain(artist.appears_on_albums.iter().flat_map(|group| group.iter().map(|uri| (uri, true))));
+
+        let mut albums: Vec<(Album, usize)> = Vec::new();
+        let mut filtered = 0;
+        for (album_uri, appears_on) in album_uris {
+            // Other artists' releases are left out without loading them
+            if appears_on && !release_types.contains(&ReleaseType::AppearsOn) {
+                filtered += 1;
+                continue;
+            }
+            let album = Album::get(&self.session(), album_uri).await?;
+            let release_type = ReleaseType::of(&album, appears_on);
+            if !release_type.is_some_and(|release_type| release_types.contains(&release_type)) {
+                debug!("<{}> skipping {:?} release {}", artist.id, album.album_type, album.name);
+                filtered += 1;
+                continue;
+            }
+            let quality = self.album_quality(&album).await;
+            match albums.iter_mut().find(|(existing, _)| existing.name == album.name) {
+                Some(existing) if quality < existing.1 => {
+                    debug!("<{}> replacing {} with {}", album.name, existing.0.id, album.id);
+                    *existing = (album, quality);
+                }
+                Some(_) => debug!("<{}> skipping duplicate release {}", album.name, album.id),
+                None => albums.push((album, quality)),
+            }
+        }
+        Ok((albums.into_iter().map(|(album, _)| album).collect(), filtered))
+    }
+
+    /// Ranks an album by the position of its first track's best format in `config.format_preference`,
+    /// lower being better.
+    async fn album_quality(&self, album: &Album) -> usize {
+        let preference = &self.config.format_preference;
+        let Some(track_uri) = album.tracks().next() else {
+            return preference.len();
+        };
+        match Track::get(&self.session(), track_uri).await {
+            Ok(track) => preference
+                .iter()
+                .position(|format| track.files.contains_key(format))
+                .unwrap_or(preference.len()),
+            Err(_) => preference.len(),
+        }
+    }
+
+    pub async fn download_playlist_by_id(
+        &self,
+        base62: &str,
+        directory: &str
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        let id = SpotifyId::from_base62(base62).map_err(DownloadError::InvalidInput)?;
+        let uri = SpotifyUri::Playlist { user: None, id };
+        self.download_playlist(&uri, directory).await
+    }
+
+    pub async fn download_playlist(
+        &self,
+        uri: &SpotifyUri,
+        directory: &str
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        let (playlist, track_uris) = self.get_playlist(uri).await.map_err(DownloadError::Metadata)?;
+        info!("Downloading Playlist: {}", playlist.name());
+        let track_uris: Vec<SpotifyUri> = track_uris
+            .into_iter()
+            .filter(|track_uri| match track_uri {
+                SpotifyUri::Track { .. } | SpotifyUri::Episode { .. } => true,
+                SpotifyUri::Local { track_title, .. } => {
+                    warn!("<{}> skipping local file entry: {}", playlist.id, track_title);
+                    false
+                }
+                _ => {
+                    warn!("<{}> skipping unsupported entry {}", playlist.id, track_uri);
+                    false
+                }
+            })
+            .collect();
+        if track_uris.is_empty() {
+            warn!("<{}> {} has no tracks or episodes that can be downloaded", playlist.id, playlist.name());
+            return Err(DownloadError::Empty(playlist.id.clone()));
+        }
+        let dirpath = self.collection_dir(directory, playlist.name());
+        info!("<{}> saved at {:?}", playlist.id, dirpath);
+        self.check_space(&track_uris, &dirpath).await?;
+        self.create_dir(&dirpath);
+        let as_album = self.config.playlist_as_album.then(|| playlist.name());
+        let outcomes = self.download_uris(&track_uris, None, as_album, &dirpath).await;
+        let downloadable = self.any_downloadable(&playlist.id, &dirpath, &outcomes);
+        if downloadable && !self.config.dry_run && !self.config.updates_existing() {
+            write_playlist_file(&dirpath, playlist.name(), &outcomes);
+            #[cfg(feature = "transcode")]
+            if self.config.concat {
+                match concat_files(&dirpath, playlist.name(), &outcomes).await {
+                    Ok(Some((path, chapters))) => {
+                        info!("<{}> joined into {:?}", playlist.id, path);
+                        if self.config.cue {
+                            write_cue(&path.with_extension("cue"), playlist.name(), "", &[(&path, chapters)]);
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => warn!("<{}> unable to join the tracks into one file: {e}", playlist.id),
+                }
+            }
+        }
+        Ok(outcomes)
+    }
+
+    /// Fetches the playlist metadata along with every item uri, requesting further pages
+    /// until the playlist's declared length is reached.
+    async fn get_playlist(&self, uri: &SpotifyUri) -> Result<(Playlist, Vec<SpotifyUri>), Error> {
+        let playlist = self.retry("playlist page", || self.get_playlist_page(uri, 0)).await?;
+        self.record_added(playlist.contents.items.iter().map(|item| (&item.id, &item.attributes.timestamp))).await;
+        let mut track_uris: Vec<SpotifyUri> = playlist.contents.items.iter().map(|item| item.id.clone()).collect();
+        let mut pages = 1;
+        while track_uris.len() < playlist.length as usize {
+            let from = track_uris.len();
+            let page = self.retry("playlist page", || self.get_playlist_page(uri, from)).await?;
+            pages += 1;
+            if page.contents.items.is_empty() {
+                warn!(
+                    "<{}> resolved {} of {} tracks",
+                    playlist.id,
+                    track_uris.len(),
+                    playlist.length
+                );
+                break;
+            }
+            self.record_added(page.contents.items.iter().map(|item| (&item.id, &item.attributes.timestamp))).await;
+            track_uris.extend(page.contents.items.iter().map(|item| item.id.clone()));
+        }
+        info!("<{}> resolved {} tracks in {pages} pages", playlist.id, track_uris.len());
+        Ok((playlist, track_uris))
+    }
+
+    /// Remembers when each of `items` was added with `config.extended_tags`
+    async fn record_added<'a>(&self, items: impl Iterator<Item = (&'a SpotifyUri, &'a Date)>) {
+        if self.config.extended_tags {
+            let mut added = self.added.lock().await;
+            added.extend(items.filter_map(|(uri, date)| Some((uri.clone(), iso_timestamp(date)?))));
+        }
+    }
+
+    async fn get_playlist_page(&self, uri: &SpotifyUri, from: usize) -> Result<Playlist, Error> {
+        let SpotifyUri::Playlist { id, .. } = uri else {
+            return Err(Error::invalid_argument("playlist_uri"));
+        };
+        let endpoint = format!(
+            "/playlist/v2/playlist/{}?from={from}&length={PLAYLIST_PAGE_SIZE}",
+            id.to_base62()?
+        );
+        let response = self.session().spclient().request(&Method::GET, &endpoint, None, None).await?;
+        let msg = SelectedListContent::parse_from_bytes(&response)?;
+        Playlist::parse(&msg, uri)
+    }
+
+    /// Downloads the user's Liked Songs, newest first. Tracks already on disk are skipped with
+    /// `OnExisting::Skip`.
+    pub async fn download_saved_tracks(
+        &self,
+        directory: &str
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        let username = self.session().username();
+        let context_uri = format!("spotify:user:{username}:collection");
+        let track_uris = self.get_context_tracks(&context_uri).await.map_err(DownloadError::Metadata)?;
+        let collection = SpotifyUri::Unknown { kind: "collection".into(), id: username };
+        if track_uris.is_empty() {
+            warn!("<{}> Liked Songs has no tracks", context_uri);
+            return Err(DownloadError::Empty(collection));
+        }
+        info!("Downloading {} Liked Songs", track_uris.len());
+        let dirpath = self.collection_dir(directory, SAVED_TRACKS_DIRECTORY);
+        info!("<{}> saved at {:?}", context_uri, dirpath);
+        let existing = match self.config.on_existing {
+            OnExisting::Skip if !self.config.updates_existing() => downloaded_ids(&dirpath),
+            _ => HashSet::new(),
+        };
+        let track_uris: Vec<SpotifyUri> = track_uris
+            .into_iter()
+            .filter(|track_uri| {
+                let downloaded = track_uri.to_id().is_ok_and(|id| existing.contains(&id));
+                if downloaded {
+                    debug!("<{}> already downloaded, skipping", track_uri);
+                }
+                !downloaded
+            })
+            .collect();
+        self.check_space(&track_uris, &dirpath).await?;
+        self.create_dir(&dirpath);
+        let outcomes = self.download_uris(&track_uris, None, None, &dirpath).await;
+        let downloadable = self.any_downloadable(&collection, &dirpath, &outcomes);
+        if self.config.album_playlist && downloadable && !self.config.dry_run && !self.config.updates_existing() {
+            write_playlist_file(&dirpath, SAVED_TRACKS_DIRECTORY, &outcomes);
+        }
+        Ok(outcomes)
+    }
+
+    /// Resolves the track uris of a context, following page urls until every page is consumed.
+    /// The collection context is returned in date-added order, newest first.
+    async fn get_context_tracks(&self, context_uri: &str) -> Result<Vec<SpotifyUri>, Error> {
+        let session = self.session();
+        let spclient = session.spclient();
+        let context = spclient.get_context(context_uri).await?;
+        let mut pages: VecDeque<ContextPage> = context.pages.into();
+        let mut track_uris = Vec::new();
+        let mut fetched = 0;
+        while let Some(page) = pages.pop_front() {
+            if page.tracks.is_empty() {
+                if let Some(page_url) = page.page_url.as_deref().filter(|url| !url.is_empty()) {
+                    pages.push_front(self.retry("context page", || self.get_context_page(page_url)).await?);
+                    fetched += 1;
+                    continue;
+                }
+            }
+            let mut added = Vec::new();
+            for track in &page.tracks {
+                match SpotifyUri::from_uri(track.uri()) {
+                    Ok(uri @ SpotifyUri::Track { .. }) => {
+                        // Liked Songs list when each was saved in seconds
+                        let date = track.metadata.get("added_at").and_then(|seconds| seconds.parse::<i64>().ok());
+                        if let Some(Ok(date)) = date.map(|seconds| Date::from_timestamp_ms(seconds * 1000)) {
+                            added.push((uri.clone(), date));
+                        }
+                        track_uris.push(uri);
+                    }
+                    _ => warn!("<{}> skipping unsupported entry {}", context_uri, track.uri()),
+                }
+            }
+            self.record_added(added.iter().map(|(uri, date)| (uri, date))).await;
+            if let Some(next_page_url) = page.next_page_url.as_deref().filter(|url| !url.is_empty()) {
+                pages.push_back(self.retry("context page", || self.get_context_page(next_page_url)).await?);
+                fetched += 1;
+            }
+        }
+        info!("<{}> resolved {} tracks, fetching {fetched} further pages", context_uri, track_uris.len());
+        Ok(track_uris)
+    }
+
+    async fn get_context_page(&self, page_url: &str) -> Result<ContextPage, Error> {
+        let response = self.session().spclient().get_next_page(page_url).await?;
+        let page = protobuf_json_mapping::parse_from_str::<ContextPage>(&String::from_utf8(response.to_vec())?)?;
+        Ok(page)
+    }
+
+    /// Creates `dirpath` unless this is a dry run.
+    fn create_dir(&self, dirpath: &Path) {
+        if !self.config.dry_run && !self.config.updates_existing() {
+            _ = create_dir_all(dirpath);
+        }
+    }
+
+    /// Description of the item `uri` with a table of its tracks or episodes and the formats
+    /// each is available in, only fetching metadata. Artists list their releases instead.
+    pub async fn info(&self, uri: &SpotifyUri) -> Result<String, DownloadError> {
+        let session = self.session();
+        let (mut text, items): (String, Vec<SpotifyUri>) = match uri {
+            SpotifyUri::Album { .. } => {
+                let album = Album::get(&session, uri).await.map_err(DownloadError::Metadata)?;
+                let mut text = format!("Album: {}\n", album.name);
+                text.push_str(&format!("Artists: {}\n", join_album_artists(&album, ", ")));
+                text.push_str(&format!("Type: {:?}\n", album.album_type));
+                if let Some(date) = release_date(&album) {
+                    text.push_str(&format!("Released: {date}\n"));
+                }
+                if !album.label.trim().is_empty() {
+                    text.push_str(&format!("Label: {}\n", album.label.trim()));
+                }
+                (text, album.tracks().cloned().collect())
+            }
+            SpotifyUri::Playlist { .. } => {
+                let (playlist, track_uris) = self.get_playlist(uri).await.map_err(DownloadError::Metadata)?;
+                (format!("Playlist: {}\n", playlist.name()), track_uris)
+            }
+            SpotifyUri::Artist { .. } => {
+                let artist = Artist::get(&session, uri).await.map_err(DownloadError::Metadata)?;
+                let (albums, filtered) = self
+                    .get_artist_albums(&artist, &self.config.release_types)
+                    .await
+                    .map_err(DownloadError::Metadata)?;
+                let rows: Vec<[String; 4]> = albums
+                    .iter()
+                    .map(|album| {
+                        [
+                            release_date(album).unwrap_or_default(),
+                            format!("{:?}", album.album_type),
+                            album.name.clone(),
+                            album.tracks().count().to_string(),
+                        ]
+                    })
+                    .collect();
+                let mut text = format!("Artist: {}\n", artist.name);
+                text.push_str(&format!("Releases: {} ({filtered} filtered out by type)\n\n", albums.len()));
+                text.push_str(&format_table(["Released", "Type", "Title", "Tracks"], &rows));
+                return Ok(text);
+            }
+            SpotifyUri::Show { .. } => {
+                let show = Show::get(&session, uri).await.map_err(DownloadError::Metadata)?;
+                (format!("Show: {}\nPublisher: {}\n", show.name, show.publisher), show.episodes.to_vec())
+            }
+            SpotifyUri::Track { .. } | SpotifyUri::Episode { .. } => (String::new(), vec![uri.clone()]),
+            _ => {
+                let e = Error::unimplemented(format!("describing {} is not supported", uri.item_type()));
+                return Err(DownloadError::InvalidInput(e));
+            }
+        };
+
+        let mut rows = Vec::with_capacity(items.len());
+        for (index, item) in items.iter().enumerate() {
+            let row = match item {
+                SpotifyUri::Episode { .. } => Episode::get(&session, item).await.map(|episode| {
+                    [
+                        (index + 1).to_string(),
+                        episode.name,
+                        episode.show_name,
+                        format_duration(episode.duration),
+                        format_names(&episode.audio),
+                    ]
+                }),
+                SpotifyUri::Track { .. } => Track::get(&session, item).await.map(|track| {
+                    [
+                        track.number.to_string(),
+                        track.name.clone(),
+                        self.join_artists(&track),
+                        format_duration(track.duration),
+                        format_names(&track.files),
+                    ]
+                }),
+                SpotifyUri::Local { track_title, artist, .. } => Ok([
+                    (index + 1).to_string(),
+                    track_title.clone(),
+                    artist.clone(),
+                    String::new(),
+                    "local file".to_string(),
+                ]),
+                _ => Err(Error::unimplemented("unsupported entry")),
+            };
+            rows.push(row.unwrap_or_else(|e| {
+                [(index + 1).to_string(), item.to_string(), String::new(), String::new(), format!("unavailable: {e}")]
+            }));
+        }
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        text.push_str(&format_table(["#", "Title", "Artists", "Duration", "Formats"], &rows));
+        Ok(text)
+    }
+
+    /// Every format each track or episode of `uri` is offered in, with the extension and data
+    /// rate it would be saved with and which one `config.format_preference` selects.
+    pub async fn list_formats(&self, uri: &SpotifyUri) -> Result<String, DownloadError> {
+        let session = self.session();
+        let items: Vec<SpotifyUri> = match uri {
+            SpotifyUri::Album { .. } => {
+                let album = Album::get(&session, uri).await.map_err(DownloadError::Metadata)?;
+                album.tracks().cloned().collect()
+            }
+            SpotifyUri::Playlist { .. } => self.get_playlist(uri).await.map_err(DownloadError::Metadata)?.1,
+            SpotifyUri::Show { .. } => {
+                let show = Show::get(&session, uri).await.map_err(DownloadError::Metadata)?;
+                show.episodes.to_vec()
+            }
+            SpotifyUri::Track { .. } | SpotifyUri::Episode { .. } => vec![uri.clone()],
+            _ => {
+                let e = Error::unimplemented(format!("listing formats of {} is not supported", uri.item_type()));
+                return Err(DownloadError::InvalidInput(e));
+            }
+        };
+
+        let mut text = String::new();
+        for (index, item) in items.iter().enumerate() {
+            let described = match item {
+                SpotifyUri::Episode { .. } => Episode::get(&session, item)
+                    .await
+                    .map(|episode| (format!("{} - {}", episode.show_name, episode.name), episode.audio)),
+                SpotifyUri::Track { .. } => Track::get(&session, item)
+                    .await
+                    .map(|track| (format!("{} - {}", self.join_artists(&track), track.name), track.files)),
+                _ => Err(Error::unimplemented("unsupported entry")),
+            };
+            if index > 0 {
+                text.push('\n');
+            }
+            match described {
+                Ok((title, files)) => {
+                    text.push_str(&format!("{title} ({item})\n"));
+                    if files.is_empty() {
+                        text.push_str("No formats\n");
+                        continue;
+                    }
+                    let rows = format_rows(&files, &self.config.format_preference);
+                    text.push_str(&format_table(["Format", "Extension", "Data rate", "Selected"], &rows));
+                }
+                Err(e) => text.push_str(&format!("{item}\nUnavailable: {e}\n")),
+            }
+        }
+        Ok(text)
+    }
+
+    /// Directory a collection called `name` is saved into. A directory template decides the
+    /// destination per track instead, so the collection folder is left out.
+    fn collection_dir(&self, directory: &str, name: &str) -> PathBuf {
+        let mut dirpath = PathBuf::from(directory);
+        if self.config.directory_template.is_none() {
+            dirpath.push(sanitize_filename(name, ""));
+        }
+        dirpath
+    }
+
+    /// Downloads the tracks and episodes in `uris` into `dirpath`, running up to
+    /// `config.concurrency` downloads at once. Outcomes are returned in the order of `uris`.
+    /// `album` is the full metadata of the album the tracks belong to, if known. With `as_album`,
+    /// tracks are tagged as the tracks of an album of that name in the order of `uris`.
+    async fn download_uris(
+        &self,
+        uris: &[SpotifyUri],
+        album: Option<&Album>,
+        as_album: Option<&str>,
+        dirpath: &Path
+    ) -> Vec<(SpotifyUri, TrackOutcome)> {
+        let overall = self.progress_bar(uris.len() as u64, "[{pos}/{len}] {wide_bar} {elapsed_precise}");
+        let mut outcomes: Vec<(usize, SpotifyUri, TrackOutcome)> = stream::iter(uris.iter().enumerate())
+            .map(|(index, uri)| {
+                let overall = &overall;
+                async move {
+                    let outcome = match uri {
+                        _ if self.cancel.is_cancelled() => TrackOutcome::Cancelled,
+                        _ => {
+                            let position = as_album.map(|name| (name, index + 1));
+                            self.download_item(uri, album, position, dirpath).await
+                        }
+                    };
+                    overall.inc(1);
+                    (index, uri.clone(), outcome)
+                }
+            })
+            .buffer_unordered(self.config.concurrency.max(1))
+            .collect()
+            .await;
+        overall.finish_and_clear();
+        outcomes.sort_by_key(|(index, ..)| *index);
+        outcomes.into_iter().map(|(_, uri, outcome)| (uri, outcome)).collect()
+    }
+
+    /// Whether any of the `outcomes` of collection `uri` isn't unavailable or failed. Otherwise
+    /// a warning is logged and the collection directory `dirpath` is removed again unless
+    /// something was saved in it. With a directory template `dirpath` is the output directory,
+    /// which is left alone.
+    fn any_downloadable(&self, uri: &SpotifyUri, dirpath: &Path, outcomes: &[(SpotifyUri, TrackOutcome)]) -> bool {
+        let downloadable = outcomes.iter().any(|(_, outcome)| {
+            !matches!(
+                outcome,
+                TrackOutcome::SkippedUnsupportedFormat | TrackOutcome::SkippedBelowMinQuality(_) | TrackOutcome::Failed(_)
+            )
+        });
+        if !downloadable {
+            warn!("<{}> none of its {} items could be downloaded", uri, outcomes.len());
+            if self.config.directory_template.is_none() {
+                _ = remove_dir(dirpath);
+            }
+        }
+        downloadable
+    }
+
+    /// Downloads the track or episode `uri`, trying again once dropped sessions have been
+    /// reconnected. `position` tags a track as the numbered track of another album, see
+    /// `download_uris`.
+    async fn download_item(
+        &self,
+        uri: &SpotifyUri,
+        album: Option<&Album>,
+        position: Option<(&str, usize)>,
+        dirpath: &Path
+    ) -> TrackOutcome {
+        let resume = self.config.resume.as_ref().filter(|_| !self.config.dry_run && !self.config.updates_existing());
+        if let Some(path) = resume.and_then(|resume| resume.completed(uri)) {
+            debug!("<{}> already saved at {:?} before the run was interrupted", uri, path);
+            let outcome = TrackOutcome::SkippedExisting(path);
+            self.emit(DownloadEvent::finished(uri, &outcome));
+            return outcome;
+        }
+        loop {
+            let seen = self.reconnects.load(Ordering::Relaxed);
+            let outcome = match uri {
+                SpotifyUri::Episode { .. } => self.download_episode_by_uri(uri, dirpath).await,
+                _ => self.download_track_at(uri, album, position, dirpath).await,
+            };
+            if !matches!(outcome, TrackOutcome::Failed(_)) {
+                let saved = match &outcome {
+                    TrackOutcome::Downloaded(file) => Some(&file.path),
+                    TrackOutcome::SkippedExisting(path) => Some(path),
+                    _ => None,
+                };
+                if let (Some(resume), Some(path)) = (resume, saved) {
+                    if let Err(e) = resume.complete(uri, path) {
+                        warn!("<{}> unable to save the resume state: {e}", uri);
+                    }
+                }
+                return outcome;
+            }
+            match self.reconnect_dropped(seen).await {
+                Ok(true) => info!("<{}> retrying after reconnecting", uri),
+                Ok(false) => return outcome,
+                Err(e) => {
+                    error!("<{}> unable to reconnect: {e}", uri);
+                    return TrackOutcome::Failed(e);
+                }
+            }
+        }
+    }
+
+    pub async fn download_track_by_uri(
+        &self,
+        uri: &SpotifyUri,
+        album: Option<&Album>,
+        dirpath: &Path
+    ) -> TrackOutcome {
+        self.download_track_at(uri, album, None, dirpath).await
+    }
+
+    /// Downloads the track `uri`, numbered and tagged as a track of the album named in
+    /// `position` when given.
+    async fn download_track_at(
+        &self,
+        uri: &SpotifyUri,
+        album: Option<&Album>,
+        position: Option<(&str, usize)>,
+        dirpath: &Path
+    ) -> TrackOutcome {
+        match Track::get(&self.session(), uri).await {
+            Ok(track) => {
+                let track = self.resolve_variant(track).await;
+                let track = self.resolve_market(track).await;
+                let mut track = self.resolve_relinking(track).await;
+                if let Some((name, number)) = position {
+                    track.album.name = name.to_string();
+                    track.number = number as i32;
+                    track.disc_number = 1;
+                }
+                self.download_track(&track, album, dirpath).await
+            }
+            Err(e) => {
+                error!("<{}> unable to load track metadata: {e}", uri);
+                let outcome = TrackOutcome::Failed(DownloadError::Metadata(e));
+                self.emit(DownloadEvent::finished(uri, &outcome));
+                outcome
+            }
+        }
+    }
+
+    /// Version of `track` available in the first of `config.market` and
+    /// `config.fallback_markets` that has one, checking the alternatives Spotify lists for the
+    /// track when it is restricted itself. `track` is returned unchanged when no markets are
+    /// configured or none of them has a version.
+    async fn resolve_market(&self, track: Track) -> Track {
+        if self.config.market.is_none() && self.config.fallback_markets.is_empty() {
+            return track;
+        }
+        let primary = self.config.market.clone().unwrap_or_else(|| self.session().country());
+        let markets: Vec<&String> = std::iter::once(&primary).chain(&self.config.fallback_markets).collect();
+        let mut alternatives: Option<Vec<Track>> = None;
+        for market in markets {
+            if is_available_in(&track.restrictions, market) {
+                info!("<{}> served in market {market}", track.id);
+                return track;
+            }
+            let alternatives = match &mut alternatives {
+                Some(alternatives) => alternatives,
+                None => alternatives.insert(self.get_alternatives(&track).await),
+            };
+            let available = alternatives
+                .iter()
+                .position(|alternative| is_available_in(&alternative.restrictions, market));
+            if let Some(index) = available {
+                let alternative = alternatives.swap_remove(index);
+                info!("<{}> served as {} in market {market}", track.id, alternative.id);
+                return alternative;
+            }
+        }
+        warn!("<{}> is restricted in every configured market", track.id);
+        track
+    }
+
+    /// `track` or the alternative Spotify lists for it that is the `config.prefer` variant,
+    /// `track` itself when there is no such alternative with any audio files.
+    async fn resolve_variant(&self, track: Track) -> Track {
+        let Some(prefer) = self.config.prefer else {
+            return track;
+        };
+        if prefer.matches(&track) {
+            debug!("<{}> is the {:?} variant", track.id, prefer);
+            return track;
+        }
+        let mut alternatives = self.get_alternatives(&track).await;
+        let preferred = alternatives
+            .iter()
+            .position(|alternative| prefer.matches(alternative) && !alternative.files.is_empty());
+        match preferred {
+            Some(index) => {
+                let alternative = alternatives.swap_remove(index);
+                info!("<{}> downloading the {:?} variant {}", track.id, prefer, alternative.id);
+                alternative
+            }
+            None => {
+                info!("<{}> has no {:?} variant, downloading it as it is", track.id, prefer);
+                track
+            }
+        }
+    }
+
+    /// `track`, or the first alternative Spotify relinks it to when `track` itself can't be
+    /// played: it has none of the wanted formats or, without configured markets, is
+    /// restricted in the account's country. `track` is returned when no alternative is playable
+    /// either.
+    async fn resolve_relinking(&self, track: Track) -> Track {
+        if self.is_playable(&track) || track.alternatives.is_empty() {
+            return track;
+        }
+        let mut alternatives = self.get_alternatives(&track).await;
+        match alternatives.iter().position(|alternative| self.is_playable(alternative)) {
+            Some(index) => {
+                let alternative = alternatives.swap_remove(index);
+                info!("<{}> is unavailable, relinked to {}", track.id, alternative.id);
+                alternative
+            }
+            None => track,
+        }
+    }
+
+    fn is_playable(&self, track: &Track) -> bool {
+        let wanted = self.config.format_preference.iter().any(|format| track.files.contains_key(format));
+        let markets = self.config.market.is_some() || !self.config.fallback_markets.is_empty();
+        wanted && (markets || is_available_in(&track.restrictions, &self.session().country()))
+    }
+
+    async fn get_alternatives(&self, track: &Track) -> Vec<Track> {
+        let mut alternatives = Vec::new();
+        for alternative_uri in track.alternatives.iter() {
+            match Track::get(&self.session(), alternative_uri).await {
+                Ok(alternative) => alternatives.push(alternative),
+                Err(e) => debug!("<{}> unable to load alternative {}: {e}", track.id, alternative_uri),
+            }
+        }
+        alternatives
+    }
+
+    /// Downloads `track` into `dirpath`. Album level tags come from `album` when given, and
+    /// from the partial album embedded in the track metadata otherwise.
+    pub async fn download_track(&self, track: &Track, album: Option<&Album>, dirpath: &Path) -> TrackOutcome {
+        let title = format!("{} - {}", self.join_artists(track), track.name);
+        self.emit(DownloadEvent::TrackStarted { uri: track.id.clone(), title });
+        let download = self.try_download_track(track, album.unwrap_or(&track.album), dirpath);
+        let outcome = match self.with_deadline(download).await {
+            Ok(outcome) => outcome,
+            Err(DownloadError::UnsupportedFormat(_)) => TrackOutcome::SkippedUnsupportedFormat,
+            Err(DownloadError::BelowMinQuality(_, format)) => TrackOutcome::SkippedBelowMinQuality(format),
+            Err(DownloadError::Cancelled) => {
+                warn!("<{}> download cancelled", track.id);
+                TrackOutcome::Cancelled
+            }
+            Err(e) => {
+                error!("<{}> download failed: {e}", track.id);
+                TrackOutcome::Failed(e)
+            }
+        };
+        if matches!(outcome, TrackOutcome::Failed(_) | TrackOutcome::Cancelled) {
+            self.release_isrc(track).await;
+        }
+        self.emit(DownloadEvent::finished(&track.id, &outcome));
+        outcome
+    }
+
+    /// Records the ISRC of `track` as downloaded with `config.dedup_isrc`, returning the track
+    /// that recorded it first when that is another track
+    async fn claim_isrc(&self, track: &Track) -> Option<SpotifyUri> {
+        let isrc = external_id(&track.external_ids, "isrc").filter(|_| self.config.dedup_isrc)?;
+        let mut isrcs = self.isrcs.lock().await;
+        let first = isrcs.entry(isrc.to_uppercase()).or_insert_with(|| track.id.clone());
+        (*first != track.id).then(|| first.clone())
+    }
+
+    /// Lets a later track with the ISRC of `track` be downloaded after `track` failed
+    async fn release_isrc(&self, track: &Track) {
+        if let Some(isrc) = external_id(&track.external_ids, "isrc").filter(|_| self.config.dedup_isrc) {
+            let mut isrcs = self.isrcs.lock().await;
+            if isrcs.get(&isrc.to_uppercase()) == Some(&track.id) {
+                isrcs.remove(&isrc.to_uppercase());
+            }
+        }
+    }
+
+    /// Runs `download` with `config.track_timeout` as its `TRACK_DEADLINE`. A download still
+    /// running at the deadline is dropped and fails with `DownloadError::TimedOut`. Its partial
+    /// file is removed once its blocking copy has stopped and let go of it.
+    async fn with_deadline(
+        &self,
+        download: impl Future<Output = Result<TrackOutcome, DownloadError>>
+    ) -> Result<TrackOutcome, DownloadError> {
+        let Some(timeout) = self.config.track_timeout else {
+            return download.await;
+        };
+        let download = TRACK_DEADLINE.scope(Instant::now() + timeout, download);
+        TRACK_SCOPE
+            .scope(TrackScope::default(), async {
+                match tokio::time::timeout(timeout, download).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        let copying = TRACK_SCOPE.with(|scope| {
+                            scope.stop.cancel();
+                            scope.copying.take()
+                        });
+                        if let Some(copying) = copying {
+                            copying.cancelled().await;
+                        }
+                        if let Some(partpath) = TRACK_SCOPE.with(|scope| scope.partial.take()) {
+                            _ = remove_file(partpath);
+                        }
+                        Err(DownloadError::TimedOut)
+                    }
+                }
+            })
+            .await
+    }
+
+    async fn try_download_track(
+        &self,
+        track: &Track,
+        album: &Album,
+        dirpath: &Path
+    ) -> Result<TrackOutcome, DownloadError> {
+        info!("Downloading Track #{}: {} ({})", track.number, track.name, track.id);
+        let track_id = match track.id {
+            SpotifyUri::Track { id } => id,
+            _ => {
+                let e = Error::invalid_argument(format!("<{}> is not a track", track.id));
+                return Err(DownloadError::InvalidInput(e));
+            }
+        };
+        let Some((format, file_id)) = select_format(&track.id, &track.files, &self.config.format_preference) else {
+            return Err(DownloadError::UnsupportedFormat(track.id.clone()));
+        };
+        if self.config.updates_existing() {
+            let (dirpath, filepath) = self.track_destination(track, format, dirpath)?;
+            return self.retag_track(track, album, &dirpath, &filepath).await;
+        }
+        let below_min_quality = self.check_quality(&track.id, format)?;
+        if let Some(first) = self.claim_isrc(track).await {
+            info!("<{}> skipped as a duplicate of {first}", track.id);
+            return Ok(TrackOutcome::SkippedDuplicate(first));
+        }
+        let (dirpath, filepath) = self.track_destination(track, format, dirpath)?;
+        let file = SavedFile {
+            path: filepath.clone(),
+            format,
+            tagged: false,
+            title: format!("{} - {}", self.join_artists(track), track.name),
+            duration: Duration::from_millis(track.duration.max(0) as u64),
+            below_min_quality,
+            on_existing: None,
+        };
+        let (filepath, on_existing) = match self.resolve_existing(&track.id, filepath) {
+            Ok(resolved) => resolved,
+            Err(path) if self.config.dry_run => {
+                return Ok(TrackOutcome::DryRun { exists: true, file: SavedFile { path, ..file } });
+            }
+            Err(path) => return Ok(TrackOutcome::SkippedExisting(path)),
+        };
+        let file = SavedFile { path: filepath.clone(), on_existing, ..file };
+        if self.config.dry_run {
+            return Ok(TrackOutcome::DryRun { exists: false, file });
+        }
+        create_dir_all(&dirpath)?;
+        let mut retried = false;
+        let tagged = loop {
+            let (audio_file, loudness) = self.open_audio(format, file_id, track_id).await?;
+            match self.save_decrypted_audio(format, track, album, audio_file, loudness, filepath.clone()).await {
+                Err(DownloadError::Corrupt(reason)) if !retried => {
+                    warn!("<{}> verification failed: {reason}, downloading it again", track.id);
+                    retried = true;
+                }
+                saved => break saved?,
+            }
+        };
+        let file = SavedFile { tagged, ..file };
+        #[cfg(feature = "sqlite")]
+        self.index_download(&track.id, external_id(&track.external_ids, "isrc"), &file);
+        Ok(TrackOutcome::Downloaded(file))
+    }
+
+    pub async fn download_show_by_id(
+        &self,
+        base62: &str,
+        directory: &str
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        let id = SpotifyId::from_base62(base62).map_err(DownloadError::InvalidInput)?;
+        let uri = SpotifyUri::Show { id };
+        let show = Show::get(&self.session(), &uri).await.map_err(DownloadError::Metadata)?;
+        self.download_show(show, directory).await
+    }
+
+    pub async fn download_show(
+        &self,
+        show: Show,
+        directory: &str
+    ) -> Result<Vec<(SpotifyUri, TrackOutcome)>, DownloadError> {
+        info!("Downloading Show: {}", show.name);
+        if show.episodes.is_empty() {
+            warn!("<{}> {} has no episodes", show.id, show.name);
+            return Err(DownloadError::Empty(show.id.clone()));
+        }
+        let dirpath = self.collection_dir(directory, &show.name);
+        info!("<{}> saved at {:?}", show.id, dirpath);
+        self.check_space(&show.episodes, &dirpath).await?;
+        self.create_dir(&dirpath);
+        let outcomes = self.download_uris(&show.episodes, None, None, &dirpath).await;
+        let downloadable = self.any_downloadable(&show.id, &dirpath, &outcomes);
+        if self.config.album_playlist && downloadable && !self.config.dry_run && !self.config.updates_existing() {
+            write_playlist_file(&dirpath, &show.name, &outcomes);
+        }
+        Ok(outcomes)
+    }
+
+    pub async fn download_episode_by_id(&self, base62: &str, directory: &str) -> Result<TrackOutcome, DownloadError> {
+        let id = SpotifyId::from_base62(base62).map_err(DownloadError::InvalidInput)?;
+        let uri = SpotifyUri::Episode { id };
+        let dirpath = PathBuf::from(directory);
+        self.create_dir(&dirpath);
+        Ok(self.download_episode_by_uri(&uri, &dirpath).await)
+    }
+
+    pub async fn download_episode_by_uri(&self, uri: &SpotifyUri, dirpath: &Path) -> TrackOutcome {
+        match Episode::get(&self.session(), uri).await {
+            Ok(episode) => self.download_episode(&episode, dirpath).await,
+            Err(e) => {
+                error!("<{}> unable to load episode metadata: {e}", uri);
+                let outcome = TrackOutcome::Failed(DownloadError::Metadata(e));
+                self.emit(DownloadEvent::finished(uri, &outcome));
+                outcome
+            }
+        }
+    }
+
+    pub async fn download_episode(&self, episode: &Episode, dirpath: &Path) -> TrackOutcome {
+        let title = format!("{} - {}", episode.show_name, episode.name);
+        self.emit(DownloadEvent::TrackStarted { uri: episode.id.clone(), title });
+        let outcome = match self.with_deadline(self.try_download_episode(episode, dirpath)).await {
+            Ok(outcome) => outcome,
+            Err(DownloadError::UnsupportedFormat(_)) => TrackOutcome::SkippedUnsupportedFormat,
+            Err(DownloadError::BelowMinQuality(_, format)) => TrackOutcome::SkippedBelowMinQuality(format),
+            Err(DownloadError::Cancelled) => {
+                warn!("<{}> download cancelled", episode.id);
+                TrackOutcome::Cancelled
+            }
+            Err(e) => {
+                error!("<{}> download failed: {e}", episode.id);
+                TrackOutcome::Failed(e)
+            }
+        };
+        self.emit(DownloadEvent::finished(&episode.id, &outcome));
+        outcome
+    }
+
+    async fn try_download_episode(&self, episode: &Episode, dirpath: &Path) -> Result<TrackOutcome, DownloadError> {
+        info!("Downloading Episode: {} ({})", episode.name, episode.id);
+        let episode_id = match episode.id {
+            SpotifyUri::Episode { id } => id,
+            _ => {
+                let e = Error::invalid_argument(format!("<{}> is not an episode", episode.id));
+                return Err(DownloadError::InvalidInput(e));
+            }
+        };
+        let Some((format, file_id)) = select_format(&episode.id, &episode.audio, &self.config.format_preference) else {
+            return Err(DownloadError::UnsupportedFormat(episode.id.clone()));
+        };
+        if self.config.updates_existing() {
+            let (dirpath, filepath) = self.episode_destination(episode, format, dirpath)?;
+            return self.retag_episode(episode, &dirpath, &filepath).await;
+        }
+        let below_min_quality = self.check_quality(&episode.id, format)?;
+        let (dirpath, filepath) = self.episode_destination(episode, format, dirpath)?;
+        let file = SavedFile {
+            path: filepath.clone(),
+            format,
+            tagged: false,
+            title: format!("{} - {}", episode.show_name, episode.name),
+            duration: Duration::from_millis(episode.duration.max(0) as u64),
+            below_min_quality,
+            on_existing: None,
+        };
+        let (filepath, on_existing) = match self.resolve_existing(&episode.id, filepath) {
+            Ok(resolved) => resolved,
+            Err(path) if self.config.dry_run => {
+                return Ok(TrackOutcome::DryRun { exists: true, file: SavedFile { path, ..file } });
+            }
+            Err(path) => return Ok(TrackOutcome::SkippedExisting(path)),
+        };
+        let file = SavedFile { path: filepath.clone(), on_existing, ..file };
+        if self.config.dry_run {
+            return Ok(TrackOutcome::DryRun { exists: false, file });
+        }
+        create_dir_all(&dirpath)?;
+        let (audio_file, _) = self.open_audio(format, file_id, episode_id).await?;
+        let tagged = self.save_decrypted_episode(format, episode, audio_file, filepath).await?;
+        let file = SavedFile { tagged, ..file };
+        #[cfg(feature = "sqlite")]
+        self.index_download(&episode.id, None, &file);
+        Ok(TrackOutcome::Downloaded(file))
+    }
+
+    /// Directory and path `track` is saved at in `format` below `dirpath`
+    fn track_destination(
+        &self,
+        track: &Track,
+        format: AudioFileFormat,
+        dirpath: &Path
+    ) -> Result<(PathBuf, PathBuf), DownloadError> {
+        let artists = self.join_artists(track);
+        let dirpath = match &self.config.directory_template {
+            Some(template) => render_directory(template, dirpath, |name| track_placeholder(track, &artists, name))
+                .map_err(DownloadError::InvalidInput)?,
+            None => dirpath.to_path_buf(),
+        };
+        let filepath = track_filepath(&self.config.filename_template, track, &artists, format, &dirpath)
+            .map_err(DownloadError::InvalidInput)?;
+        Ok((dirpath, filepath))
+    }
+
+    /// Directory and path `episode` is saved at in `format` below `dirpath`
+    fn episode_destination(
+        &self,
+        episode: &Episode,
+        format: AudioFileFormat,
+        dirpath: &Path
+    ) -> Result<(PathBuf, PathBuf), DownloadError> {
+        let dirpath = match &self.config.directory_template {
+            Some(template) => render_directory(template, dirpath, |name| episode_placeholder(episode, name))
+                .map_err(DownloadError::InvalidInput)?,
+            None => dirpath.to_path_buf(),
+        };
+        let filepath = episode_filepath(episode, format, &dirpath).map_err(DownloadError::InvalidInput)?;
+        Ok((dirpath, filepath))
+    }
+
+    /// Fails with `DownloadError::InsufficientSpace` when the items of `uris` that aren't
+    /// downloaded below `dirpath` yet are estimated to need more than its free space. Sizes are
+    /// estimated from the data rate of the selected format and the duration.
+    async fn check_space(&self, uris: &[SpotifyUri], dirpath: &Path) -> Result<(), DownloadError> {
+        if !self.config.space_check || self.config.dry_run || self.config.updates_existing() {
+            return Ok(());
+        }
+        let Some(available) = available_space(dirpath) else {
+            debug!("Unable to determine the free space at {:?}, skipping the space check", dirpath);
+            return Ok(());
+        };
+        let needed = stream::iter(uris)
+            .map(|uri| self.estimate_size(uri, dirpath))
+            .buffer_unordered(self.config.concurrency.max(1))
+            .fold(0, |needed, size| async move { needed + size.unwrap_or(0) })
+            .await;
+        debug!("{} needed for {} items, {} free at {:?}", HumanBytes(needed), uris.len(), HumanBytes(available), dirpath);
+        if needed > available {
+            return Err(DownloadError::InsufficientSpace { needed, available });
+        }
+        Ok(())
+    }
+
+    /// Estimated size of `uri` in bytes, 0 when it is already downloaded below `dirpath`.
+    /// `None` when it can't be downloaded at all.
+    async fn estimate_size(&self, uri: &SpotifyUri, dirpath: &Path) -> Option<u64> {
+        let session = self.session();
+        let find_format = |files: &AudioFiles| {
+            self.config.format_preference.iter().copied().find(|format| files.contains_key(format))
+        };
+        let (format, duration, destination) = match uri {
+            SpotifyUri::Episode { .. } => {
+                let episode = Episode::get(&session, uri).await.ok()?;
+                let format = find_format(&episode.audio)?;
+                (format, episode.duration, self.episode_destination(&episode, format, dirpath))
+            }
+            _ => {
+                let track = Track::get(&session, uri).await.ok()?;
+                let format = find_format(&track.files)?;
+                (format, track.duration, self.track_destination(&track, format, dirpath))
+            }
+        };
+        if destination.is_ok_and(|(_, filepath)| self.is_downloaded(&filepath)) {
+            return Some(0);
+        }
+        Some(format_data_rate(format) as u64 * duration.max(0) as u64 / 1000)
+    }
+
+    /// Artists of `track` as `config.artist_separator` and `config.featured_artists` join them
+    fn join_artists(&self, track: &Track) -> String {
+        join_artists(track, &self.config.artist_separator, self.config.featured_artists)
+    }
+
+    /// Whether `format` ranks below `config.min_quality`, which is refused with
+    /// `config.strict_quality`.
+    fn check_quality(&self, uri: &SpotifyUri, format: AudioFileFormat) -> Result<bool, DownloadError> {
+        let rank = |format| FORMAT_PREFERENCE.iter().position(|preferred| *preferred == format);
+        let Some(min_quality) = self.config.min_quality else {
+            return Ok(false);
+        };
+        if rank(format) <= rank(min_quality) {
+            return Ok(false);
+        }
+        if self.config.strict_quality {
+            warn!("<{}> is only available in {:?}, below {:?}, skipping", uri, format, min_quality);
+            return Err(DownloadError::BelowMinQuality(uri.clone(), format));
+        }
+        warn!("<{}> is only available in {:?}, below {:?}", uri, format, min_quality);
+        Ok(true)
+    }
+
+    /// Returns whether `filepath` already holds a download that shouldn't be overwritten.
+    fn is_downloaded(&self, filepath: &Path) -> bool {
+        self.config.on_existing == OnExisting::Skip && is_nonempty_file(filepath)
+    }
+
+    /// Path the download of `uri` planned at `filepath` is saved at per `config.on_existing`,
+    /// along with what was done about an existing file there. Fails with the path of the
+    /// existing download when it is kept, which with `config.index` may be anywhere. Indexed
+    /// files that have been deleted since don't count.
+    fn resolve_existing(&self, uri: &SpotifyUri, filepath: PathBuf) -> Result<(PathBuf, Option<OnExisting>), PathBuf> {
+        #[cfg(feature = "sqlite")]
+        if let (Some(index), OnExisting::Skip) = (&self.config.index, self.config.on_existing) {
+            match index.path(uri) {
+                Ok(Some(path)) if is_nonempty_file(&path) => {
+                    info!("<{}> already saved at {:?} according to the index, skipping", uri, path);
+                    return Err(path);
+                }
+                Ok(Some(path)) => debug!("<{}> indexed at {:?}, which is gone", uri, path),
+                Ok(None) => {}
+                Err(e) => warn!("<{}> unable to look up the index: {e}", uri),
+            }
+        }
+        if !is_nonempty_file(&filepath) {
+            return Ok((filepath, None));
+        }
+        match self.config.on_existing {
+            OnExisting::Skip => {
+                info!("<{}> already saved at {:?}, skipping", uri, filepath);
+                Err(filepath)
+            }
+            OnExisting::Overwrite => Ok((filepath, Some(OnExisting::Overwrite))),
+            OnExisting::Rename => Ok((numbered_path(&filepath), Some(OnExisting::Rename))),
+        }
+    }
+
+    /// Existing download of `uri` planned at `filepath`: the path in `config.index`, the planned
+    /// path itself or a file in `dirpath` named with the id of `uri`, which may have been saved
+    /// in another format or under an older template.
+    fn find_existing(&self, uri: &SpotifyUri, dirpath: &Path, filepath: &Path) -> Option<PathBuf> {
+        #[cfg(feature = "sqlite")]
+        if let Some(index) = &self.config.index {
+            match index.path(uri) {
+                Ok(Some(path)) if is_nonempty_file(&path) => return Some(path),
+                Ok(_) => {}
+                Err(e) => warn!("<{}> unable to look up the index: {e}", uri),
+            }
+        }
+        if is_nonempty_file(filepath) {
+            return Some(filepath.to_path_buf());
+        }
+        find_download(dirpath, &uri.to_id().ok()?)
+    }
+
+    /// Rewrites the tags of the existing download of `track` from its current metadata,
+    /// without fetching the audio.
+    async fn retag_track(
+        &self,
+        track: &Track,
+        album: &Album,
+        dirpath: &Path,
+        filepath: &Path
+    ) -> Result<TrackOutcome, DownloadError> {
+        let Some(filepath) = self.find_existing(&track.id, dirpath, filepath) else {
+            warn!("<{}> not found at {:?}, nothing to retag", track.id, filepath);
+            return Ok(TrackOutcome::SkippedNotDownloaded);
+        };
+        if self.config.cover_only {
+            return self.backfill_cover(&track.id, track_covers(track, album), filepath).await;
+        }
+        let file_extension = filepath.extension().unwrap_or_default().to_string_lossy().into_owned();
+        let lyrics = self.get_lyrics(track).await;
+        let plain_lyrics = lyrics
+            .as_ref()
+            .filter(|_| self.config.embed_lyrics || self.config.lyrics == LyricsMode::Plain)
+            .map(plain_lyrics);
+        let mut tag = self
+            .track_tag(file_extension, track, album, plain_lyrics, None, &filepath)
+            .await
+            .map_err(DownloadError::Tag)?;
+        keep_stream_tags(&mut tag, &filepath);
+        tag.save_to_path(&filepath, WriteOptions::default()).map_err(|e| DownloadError::Tag(Error::internal(e)))?;
+        if let (Some(lyrics), LyricsMode::Synced) = (&lyrics, self.config.lyrics) {
+            self.save_lrc(track, album, &self.join_artists(track), lyrics, &filepath.with_extension("lrc"));
+        }
+        info!("<{}> retagged {:?}", track.id, filepath);
+        Ok(TrackOutcome::Retagged(filepath))
+    }
+
+    /// Embeds the cover of `uri` into the existing download `filepath` unless it has one
+    /// already, leaving its other tags alone. With `config.save_cover` the cover is saved next
+    /// to it either way.
+    async fn backfill_cover(
+        &self,
+        uri: &SpotifyUri,
+        covers: &Images,
+        filepath: PathBuf
+    ) -> Result<TrackOutcome, DownloadError> {
+        let tagged_file = lofty::read_from_path(&filepath).map_err(|e| DownloadError::Tag(Error::internal(e)))?;
+        let tag_type = tagged_file.primary_tag_type();
+        let mut tag = tagged_file.tag(tag_type).cloned().unwrap_or_else(|| Tag::new(tag_type));
+        let has_cover = tag.pictures().iter().any(|picture| picture.pic_type() == PictureType::CoverFront);
+        if has_cover && !self.config.save_cover {
+            debug!("<{}> {:?} has a cover already", uri, filepath);
+            return Ok(TrackOutcome::SkippedExisting(filepath));
+        }
+        let mut covered = Tag::new(tag_type);
+        self.push_cover(&mut covered, uri, covers, &filepath).await.map_err(DownloadError::Tag)?;
+        let Some(picture) = covered.pictures().first().filter(|_| !has_cover) else {
+            return Ok(TrackOutcome::SkippedExisting(filepath));
+        };
+        tag.push_picture(picture.clone());
+        tag.save_to_path(&filepath, WriteOptions::default()).map_err(|e| DownloadError::Tag(Error::internal(e)))?;
+        info!("<{}> cover embedded into {:?}", uri, filepath);
+        Ok(TrackOutcome::Retagged(filepath))
+    }
+
+    /// Adds the missing covers of the files below `dirpath`, see `DownloaderConfig::cover_only`.
+    /// Files are matched to tracks and episodes by the id in their name, their embedded
+    /// open.spotify.com link or else the ISRC in their tags.
+    pub async fn backfill_covers(&self, dirpath: &Path) -> Vec<(SpotifyUri, TrackOutcome)> {
+        let mut outcomes = Vec::new();
+        for filepath in audio_files(dirpath) {
+            if self.cancel.is_cancelled() {
+                break;
+            }
+            let Ok(tagged_file) = lofty::read_from_path(&filepath) else {
+                debug!("Skipping {:?}, which isn't an audio file", filepath);
+                continue;
+            };
+            let Some(uri) = self.identify_file(&filepath, tagged_file.primary_tag()).await else {
+                warn!("Unable to tell which track {:?} is, skipping it", filepath);
+                continue;
+            };
+            let covers = match &uri {
+                SpotifyUri::Episode { .. } => Episode::get(&self.session(), &uri).await.map(|episode| episode.covers),
+                _ => Track::get(&self.session(), &uri).await.map(|track| track.album.covers),
+            };
+            let outcome = match covers {
+                Ok(covers) => self.backfill_cover(&uri, &covers, filepath).await,
+                Err(e) => Err(DownloadError::Metadata(e)),
+            };
+            let outcome = outcome.unwrap_or_else(|e| {
+                error!("<{}> unable to add the cover: {e}", uri);
+                TrackOutcome::Failed(e)
+            });
+            self.emit(DownloadEvent::finished(&uri, &outcome));
+            outcomes.push((uri, outcome));
+        }
+        outcomes
+    }
+
+    /// Track or episode saved in `filepath` with `tag`, see `backfill_covers`
+    async fn identify_file(&self, filepath: &Path, tag: Option<&Tag>) -> Option<SpotifyUri> {
+        let name = filepath.file_name().unwrap_or_default().to_string_lossy();
+        if let Some(id) = embedded_id(&name).and_then(|id| SpotifyId::from_base62(id).ok()) {
+            let track = SpotifyUri::Track { id };
+            if Track::get(&self.session(), &track).await.is_ok() {
+                return Some(track);
+            }
+            return Some(SpotifyUri::Episode { id });
+        }
+        let tag = tag?;
+        let url = tag.get_string(&custom_key(tag.tag_type(), SPOTIFY_URL_KEY));
+        if let Some(uri) = url.and_then(|url| parse_reference(url).ok()) {
+            return Some(uri);
+        }
+        let isrc = tag.get_string(&ItemKey::Isrc)?;
+        match self.retry("ISRC search", || self.search_isrc(isrc)).await {
+            Ok(uri) => uri,
+            Err(e) => {
+                warn!("Unable to look up ISRC {isrc}: {e}");
+                None
+            }
+        }
+    }
+
+    /// Track with the ISRC `isrc`, looked up through the Web API search
+    async fn search_isrc(&self, isrc: &str) -> Result<Option<SpotifyUri>, Error> {
+        let url = format!("{SEARCH_API_URL}?type=track&limit=1&q=isrc:{}", isrc.trim());
+        let results = self.fetch_web_api(&url).await?;
+        let uri = results["tracks"]["items"][0]["uri"].as_str();
+        uri.map(SpotifyUri::from_uri).transpose()
+    }
+
+    /// Rewrites the tags of the existing download of `episode`, see `retag_track`
+    async fn retag_episode(
+        &self,
+        episode: &Episode,
+        dirpath: &Path,
+        filepath: &Path
+    ) -> Result<TrackOutcome, DownloadError> {
+        let Some(filepath) = self.find_existing(&episode.id, dirpath, filepath) else {
+            warn!("<{}> not found at {:?}, nothing to retag", episode.id, filepath);
+            return Ok(TrackOutcome::SkippedNotDownloaded);
+        };
+        if self.config.cover_only {
+            return self.backfill_cover(&episode.id, &episode.covers, filepath).await;
+        }
+        let file_extension = filepath.extension().unwrap_or_default().to_string_lossy().into_owned();
+        let mut tag = self.episode_tag(file_extension, episode, &filepath).await.map_err(DownloadError::Tag)?;
+        keep_stream_tags(&mut tag, &filepath);
+        tag.save_to_path(&filepath, WriteOptions::default()).map_err(|e| DownloadError::Tag(Error::internal(e)))?;
+        info!("<{}> retagged {:?}", episode.id, filepath);
+        Ok(TrackOutcome::Retagged(filepath))
+    }
+
+    /// Records the download of `uri` in `config.index`, failures are only logged
+    #[cfg(feature = "sqlite")]
+    fn index_download(&self, uri: &SpotifyUri, isrc: Option<&str>, file: &SavedFile) {
+        if let Some(index) = &self.config.index {
+            if let Err(e) = index.record(uri, isrc, file) {
+                warn!("<{}> unable to record the download in the index: {e}", uri);
+            }
+        }
+    }
+
+    async fn open_audio(
+        &self,
+        format: AudioFileFormat,
+        file_id: FileId,
+        id: SpotifyId
+    ) -> Result<(Subfile<AudioDecrypt<AudioFile>>, Option<Loudness>), DownloadError> {
+        let bytes_per_second = format_data_rate(format);
+        let session = &self.audio_session();
+        debug!("Loading {} with the account of {}", file_id, session.username());
+        let encrypted_file = self
+            .retry("audio file", || AudioFile::open(session, file_id, bytes_per_second))
+            .await?;
+        let stream_loader_controller = encrypted_file.get_stream_loader_controller()?;
+        stream_loader_controller.set_stream_mode();
+        if self.config.prefetch == Prefetch::Whole {
+            // Requests everything from the current read position, which is the start, without
+            // waiting for any of it
+            stream_loader_controller.fetch_next_and_wait(stream_loader_controller.len(), 0)?;
+        }
+        let key = self
+            .retry("audio key", || session.audio_key().request(id, file_id))
+            .await
+            .map_err(DownloadError::AudioKey)?;
+        let mut decrypted_file = AudioDecrypt::new(Some(key), encrypted_file);
+        let is_ogg_vorbis = AudioFiles::is_ogg_vorbis(format);
+        let is_flac = AudioFiles::is_flac(format);
+        let length = stream_loader_controller.len() as u64;
+        // Like copying, reading the loudness and finding where the stream starts block until the
+        // range has been fetched
+        task::spawn_blocking(move || -> Result<_, DownloadError> {
+            let loudness = match is_ogg_vorbis.then(|| Loudness::read(&mut decrypted_file)) {
+                Some(Ok(loudness)) => Some(loudness),
+                Some(Err(e)) => {
+                    warn!("Unable to read loudness data, skipping ReplayGain tags: {e}");
+                    None
+                }
+                None => None,
+            };
+            let offset = if is_ogg_vorbis {
+                match ogg_offset(&mut decrypted_file)? {
+                    Some(SPOTIFY_OGG_HEADER_END) => SPOTIFY_OGG_HEADER_END,
+                    Some(offset) => {
+                        warn!("<{}> Ogg stream starts at byte {offset}, skipping the bytes before it", file_id);
+                        offset
+                    }
+                    None => {
+                        warn!("<{}> no Ogg page follows the Spotify header, the saved file may not play", file_id);
+                        SPOTIFY_OGG_HEADER_END
+                    }
+                }
+            } else if is_flac {
+                match flac_offset(&mut decrypted_file)? {
+                    Some(0) => 0,
+                    Some(offset) => {
+                        warn!("<{}> FLAC stream starts at byte {offset}, skipping the bytes before it", file_id);
+                        offset
+                    }
+                    None => {
+                        let e = format!("<{}> is not a FLAC stream", file_id);
+                        return Err(DownloadError::Io(io::Error::new(io::ErrorKind::InvalidData, e)));
+                    }
+                }
+            } else {
+                0
+            };
+            Ok((Subfile::new(decrypted_file, offset, length - offset)?, loudness))
+        })
+        .await
+        .map_err(Error::internal)?
+    }
+
+    /// Writes the untagged audio of the track or episode `uri` to `sink`, e.g. to pipe it
+    /// into a player, and returns its format. Nothing is saved to disk.
+    pub async fn download_to_writer<W: Write + Send + 'static>(
+        &self,
+        uri: &SpotifyUri,
+        sink: W
+    ) -> Result<AudioFileFormat, DownloadError> {
+        let unplayable = |uri: &SpotifyUri| {
+            let e = Error::invalid_argument(format!("<{uri}> is neither a track nor an episode"));
+            DownloadError::InvalidInput(e)
+        };
+        let (uri, files) = match uri {
+            SpotifyUri::Track { .. } => {
+                let track = Track::get(&self.session(), uri).await.map_err(DownloadError::Metadata)?;
+                let track = self.resolve_variant(track).await;
+                let track = self.resolve_market(track).await;
+                let track = self.resolve_relinking(track).await;
+                (track.id, track.files)
+            }
+            SpotifyUri::Episode { .. } => {
+                let episode = Episode::get(&self.session(), uri).await.map_err(DownloadError::Metadata)?;
+                (episode.id, episode.audio)
+            }
+            _ => return Err(unplayable(uri)),
+        };
+        let (SpotifyUri::Track { id } | SpotifyUri::Episode { id }) = uri else {
+            return Err(unplayable(&uri));
+        };
+        let Some((format, file_id)) = select_format(&uri, &files, &self.config.format_preference) else {
+            return Err(DownloadError::UnsupportedFormat(uri.clone()));
+        };
+        self.check_quality(&uri, format)?;
+        let (audio_file, _) = self.open_audio(format, file_id, id).await?;
+        self.copy_audio(&uri, audio_file, sink, uri.to_string()).await?;
+        info!("<{}> written as {:?}", uri, format);
+        Ok(format)
+    }
+
+    /// Copies the decrypted stream into `filepath`, see `copy_audio`.
+    async fn write_audio(
+        &self,
+        uri: &SpotifyUri,
+        audio_file: Subfile<AudioDecrypt<AudioFile>>,
+        filepath: &Path
+    ) -> Result<(), DownloadError> {
+        let label = filepath.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        self.copy_audio(uri, audio_file, File::create(filepath)?, label).await?;
+        info!("Decrypted content saved to {:?}", filepath);
+        Ok(())
+    }
+
+    /// Copies the decrypted stream into `sink` on the blocking thread pool, since reads block
+    /// until the requested range has been fetched. Both ends are buffered to keep the number
+    /// of reads through the decryption and of writes to disk low. Reads are held back by the
+    /// shared throttle with `config.max_rate`. `label` names the progress bar. A download timing
+    /// out in `with_deadline` stops the copy after the read in progress.
+    async fn copy_audio<W: Write + Send + 'static>(
+        &self,
+        uri: &SpotifyUri,
+        mut audio_file: Subfile<AudioDecrypt<AudioFile>>,
+        sink: W,
+        label: String
+    ) -> Result<(), DownloadError> {
+        let total = audio_file.length;
+        let events = self.events.clone();
+        let cancel = self.cancel.clone();
+        let throttle = self.throttle.clone();
+        let max_rate = self.config.max_rate;
+        let deadline = TRACK_DEADLINE.try_with(|deadline| *deadline).ok();
+        let (stop, copying) = TRACK_SCOPE
+            .try_with(|scope| {
+                let copying = CancellationToken::new();
+                scope.copying.replace(Some(copying.clone()));
+                (scope.stop.clone(), copying)
+            })
+            .unwrap_or_default();
+        let mut throttled = 0;
+        let uri = uri.clone();
+        let bar = self.progress_bar(audio_file.length, "{msg:40!} {bar:30} {bytes}/{total_bytes}");
+        bar.set_message(label);
+        let written = task::spawn_blocking(move || -> io::Result<()> {
+            // Dropped last, after the sink has been closed
+            let _copying = copying.drop_guard();
+            let reporting = ReportingReader {
+                inner: bar.wrap_read(&mut audio_file),
+                read: 0,
+                on_read: |bytes| {
+                    if cancel.is_cancelled() {
+                        return Err(io::Error::other(DownloadError::Cancelled));
+                    }
+                    if stop.is_cancelled() || deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                        return Err(io::Error::other(DownloadError::TimedOut));
+                    }
+                    if let Some(max_rate) = max_rate {
+                        throttle.take(bytes - throttled, max_rate);
+                        throttled = bytes;
+                    }
+                    if let Some(events) = &events {
+                        _ = events.send(DownloadEvent::Progress { uri: uri.clone(), bytes, total });
+                    }
+                    Ok(())
+                },
+            };
+            let mut reader = BufReader::with_capacity(COPY_BUFFER_SIZE, reporting);
+            let mut writer = BufWriter::with_capacity(COPY_BUFFER_SIZE, sink);
+            copy(&mut reader, &mut writer)?;
+            writer.flush()?;
+            bar.finish_and_clear();
+            Ok(())
+        })
+        .await
+        .map_err(Error::internal)?;
+        match written {
+            Err(_) if self.cancel.is_cancelled() => Err(DownloadError::Cancelled),
+            Err(_) if deadline.is_some_and(|deadline| Instant::now() >= deadline) => Err(DownloadError::TimedOut),
+            written => Ok(written?),
+        }
+    }
+
+    fn progress_bar(&self, length: u64, template: &str) -> ProgressBar {
+        if !self.config.show_progress {
+            return ProgressBar::hidden();
+        }
+        let bar = ProgressBar::new(length);
+        if let Ok(style) = ProgressStyle::with_template(template) {
+            bar.set_style(style);
+        }
+        self.progress.add(bar)
+    }
+
+    async fn save_decrypted_audio(
+        &self,
+        format: AudioFileFormat,
+        track: &Track,
+        album: &Album,
+        audio_file: Subfile<AudioDecrypt<AudioFile>>,
+        loudness: Option<Loudness>,
+        filepath: PathBuf
+    ) -> Result<bool, DownloadError> {
+        let file_extension = get_extension_from_format(format);
+        let artists = self.join_artists(track);
+        let lyrics = self.get_lyrics(track).await;
+        let plain_lyrics = lyrics
+            .as_ref()
+            .filter(|_| self.config.embed_lyrics || self.config.lyrics == LyricsMode::Plain)
+            .map(plain_lyrics);
+
+        let partpath = partial_path(&filepath);
+        _ = TRACK_SCOPE.try_with(|scope| scope.partial.replace(Some(partpath.clone())));
+        let saved = async {
+            self.write_audio(&track.id, audio_file, &partpath).await?;
+            if self.config.verify && AudioFiles::is_flac(format) {
+                let path = partpath.clone();
+                task::spawn_blocking(move || verify_flac(&path))
+                    .await
+                    .map_err(Error::internal)?
+                    .map_err(DownloadError::Corrupt)?;
+                debug!("<{}> verified {:?}", track.id, partpath);
+            }
+            let mut tag = self
+                .track_tag(file_extension, track, album, plain_lyrics, loudness, &filepath)
+                .await
+                .map_err(DownloadError::Tag)?;
+            push_format(&mut tag, format);
+            let tagged = save_tag(&tag, &partpath);
+            rename(&partpath, &filepath)?;
+            Ok((tag, tagged))
+        };
+        let (tag, tagged) = discard_partial(&partpath, saved.await)?;
+
+        if let (Some(lyrics), LyricsMode::Synced) = (&lyrics, self.config.lyrics) {
+            self.save_lrc(track, album, &artists, lyrics, &filepath.with_extension("lrc"));
+        }
+        if self.config.nfo {
+            write_nfo(&filepath.with_extension("nfo"), "song", &track_nfo_fields(&tag));
+        }
+
+        // The FLAC download is complete either way, so a failed decode is only reported
+        #[cfg(feature = "pcm")]
+        match self.config.pcm {
+            Some(pcm) if AudioFiles::is_flac(format) => {
+                if let Err(e) = pcm.run(&filepath, tag.clone()).await {
+                    warn!("<{}> unable to decode to {:?}: {e}", track.id, pcm);
+                }
+            }
+            Some(pcm) => warn!("<{}> {:?} is lossy, not decoding it to {:?}", track.id, format, pcm),
+            None => {}
+        }
+
+        #[cfg(feature = "transcode")]
+        if let Some(transcode) = &self.config.transcode {
+            transcode.run(&filepath, tag).await.map_err(DownloadError::Transcode)?;
+        }
+        #[cfg(not(feature = "transcode"))]
+        drop(tag);
+
+        Ok(tagged)
+    }
+
+    /// Lyrics of `track` unless lyrics are neither saved nor embedded. Tracks without lyrics
+    /// yield `None`.
+    async fn get_lyrics(&self, track: &Track) -> Option<Lyrics> {
+        let wanted = self.config.lyrics != LyricsMode::None || self.config.embed_lyrics;
+        if !wanted || !track.has_lyrics {
+            return None;
+        }
+        let SpotifyUri::Track { id } = &track.id else {
+            return None;
+        };
+        let session = self.session();
+        match self.retry("lyrics", || Lyrics::get(&session, id)).await {
+            Ok(lyrics) => Some(lyrics),
+            Err(e) => {
+                debug!("<{}> has no lyrics: {e}", track.id);
+                None
+            }
+        }
+    }
+
+    /// Writes line-synced `lyrics` to `lrcpath`. Unsynced lyrics have no timestamps to write,
+    /// so they are skipped.
+    fn save_lrc(&self, track: &Track, album: &Album, artists: &str, lyrics: &Lyrics, lrcpath: &Path) {
+        if lyrics.lyrics.sync_type != SyncType::LineSynced {
+            debug!("<{}> lyrics are not synced, skipping {:?}", track.id, lrcpath);
+            return;
+        }
+        let mut lrc = format!("[ar:{}]\n[al:{}]\n[ti:{}]\n", artists, album.name, track.name);
+        for line in &lyrics.lyrics.lines {
+            let Ok(start) = line.start_time_ms.parse::<u64>() else {
+                continue;
+            };
+            lrc.push_str(&format!(
+                "[{:02}:{:02}.{:02}]{}\n",
+                start / 60_000,
+                start / 1000 % 60,
+                start % 1000 / 10,
+                line.words
+            ));
+        }
+        match write(lrcpath, lrc) {
+            Ok(()) => info!("Lyrics saved to {:?}", lrcpath),
+            Err(e) => warn!("Unable to write lyrics to {:?}: {}", lrcpath, e),
+        }
+    }
+
+    async fn save_decrypted_episode(
+        &self,
+        format: AudioFileFormat,
+        episode: &Episode,
+        audio_file: Subfile<AudioDecrypt<AudioFile>>,
+        filepath: PathBuf
+    ) -> Result<bool, DownloadError> {
+        let file_extension = get_extension_from_format(format);
+        let partpath = partial_path(&filepath);
+        _ = TRACK_SCOPE.try_with(|scope| scope.partial.replace(Some(partpath.clone())));
+        let saved = async {
+            self.write_audio(&episode.id, audio_file, &partpath).await?;
+            let mut tag = self.episode_tag(file_extension, episode, &filepath).await.map_err(DownloadError::Tag)?;
+            push_format(&mut tag, format);
+            let tagged = save_tag(&tag, &partpath);
+            rename(&partpath, &filepath)?;
+            Ok(tagged)
+        };
+        discard_partial(&partpath, saved.await)
+    }
+
+    /// Builds the tag for `track`, `filepath` is only used to place a saved cover.
+    async fn track_tag(
+        &self,
+        file_extension: String,
+        track: &Track,
+        album: &Album,
+        lyrics: Option<String>,
+        loudness: Option<Loudness>,
+        filepath: &Path
+    ) -> Result<Tag, Error> {
+        let artists = self.join_artists(track);
+        let mut tag = Tag::new(tag_type_for_extension(&file_extension));
+        tag.insert(TagItem::new(ItemKey::TrackTitle, ItemValue::Text(track.name.clone())));
+        tag.insert(TagItem::new(ItemKey::AlbumTitle, ItemValue::Text(track.album.name.clone())));
+        let album_artists = join_album_artists(album, &self.config.album_artist_separator);
+        let album_artists = if album_artists.is_empty() { artists.clone() } else { album_artists };
+        tag.insert(TagItem::new(ItemKey::TrackArtist, ItemValue::Text(artists)));
+        tag.insert(TagItem::new(ItemKey::AlbumArtist, ItemValue::Text(album_artists)));
+        tag.insert(TagItem::new(ItemKey::TrackNumber, ItemValue::Text(track.number.to_string())));
+        tag.insert(TagItem::new(ItemKey::DiscNumber, ItemValue::Text(track.disc_number.to_string())));
+        // The album embedded in track metadata has no discs, totals are only known from the full album
+        if let Some(disc) = album.discs.iter().find(|disc| disc.number == track.disc_number) {
+            tag.insert(TagItem::new(ItemKey::TrackTotal, ItemValue::Text(disc.tracks.len().to_string())));
+            tag.insert(TagItem::new(ItemKey::DiscTotal, ItemValue::Text(album.discs.len().to_string())));
+        }
+        if let Some(isrc) = external_id(&track.external_ids, "isrc") {
+            tag.insert(TagItem::new(ItemKey::Isrc, ItemValue::Text(isrc.to_uppercase())));
+        }
+        let track_id_key = custom_key(tag.tag_type(), SPOTIFY_TRACK_ID_KEY);
+        tag.insert_unchecked(TagItem::new(track_id_key, ItemValue::Text(track.id.to_uri()?)));
+        self.push_url(&mut tag, &track.id)?;
+        push_media_info(&mut tag, track.duration);
+        if let Some(release_date) = release_date(album) {
+            tag.insert(TagItem::new(ItemKey::Year, ItemValue::Text(album.date.year().to_string())));
+            tag.insert(TagItem::new(ItemKey::RecordingDate, ItemValue::Text(release_date)));
+        }
+
+        if let Some(lyrics) = lyrics {
+            tag.insert(TagItem::new(ItemKey::Lyrics, ItemValue::Text(lyrics)));
+        }
+        if let Some(loudness) = loudness {
+            loudness.apply(&mut tag);
+        }
+        if let Some(copyright) = copyright_message(album) {
+            tag.insert(TagItem::new(ItemKey::CopyrightMessage, ItemValue::Text(copyright)));
+        }
+        if !album.label.trim().is_empty() {
+            tag.insert(TagItem::new(ItemKey::Label, ItemValue::Text(album.label.trim().to_string())));
+        }
+        // Releases carry a UPC, older ones sometimes only an EAN
+        let barcode = external_id(&album.external_ids, "upc").or_else(|| external_id(&album.external_ids, "ean"));
+        if let Some(barcode) = barcode {
+            tag.insert(TagItem::new(ItemKey::Barcode, ItemValue::Text(barcode.to_string())));
+        }
+        if let Some(catalog_number) = external_id(&album.external_ids, "catalog") {
+            tag.insert(TagItem::new(ItemKey::CatalogNumber, ItemValue::Text(catalog_number.to_string())));
+        }
+        if self.config.force_compilation || is_compilation(album) {
+            tag.insert(TagItem::new(ItemKey::FlagCompilation, ItemValue::Text("1".to_string())));
+        }
+        if let Some(artist) = track.artists.first() {
+            for genre in self.get_genres(&artist.id).await.into_iter().take(self.config.genre_count) {
+                tag.push(TagItem::new(ItemKey::Genre, ItemValue::Text(genre)));
+            }
+        }
+
+        self.get_credits(&track.id).await.apply(&mut tag);
+        if self.config.extended_tags {
+            let popularity_key = custom_key(tag.tag_type(), SPOTIFY_POPULARITY_KEY);
+            tag.insert_unchecked(TagItem::new(popularity_key, ItemValue::Text(track.popularity.to_string())));
+            if let Some(added) = self.added.lock().await.get(&track.id) {
+                let added_key = custom_key(tag.tag_type(), SPOTIFY_ADDED_KEY);
+                tag.insert_unchecked(TagItem::new(added_key, ItemValue::Text(added.clone())));
+            }
+        }
+
+        self.push_cover(&mut tag, &track.id, track_covers(track, album), filepath).await?;
+
+        Ok(tag)
+    }
+
+    async fn episode_tag(
+        &self,
+        file_extension: String,
+        episode: &Episode,
+        filepath: &Path
+    ) -> Result<Tag, Error> {
+        let publish_date = episode.publish_time;
+        let publish_date = format!(
+            "{:04}-{:02}-{:02}",
+            publish_date.year(),
+            publish_date.month() as u8,
+            publish_date.day()
+        );
+        let mut tag = Tag::new(tag_type_for_extension(&file_extension));
+        tag.insert(TagItem::new(ItemKey::TrackTitle, ItemValue::Text(episode.name.clone())));
+        tag.insert(TagItem::new(ItemKey::AlbumTitle, ItemValue::Text(episode.show_name.clone())));
+        tag.insert(TagItem::new(ItemKey::RecordingDate, ItemValue::Text(publish_date)));
+        self.push_url(&mut tag, &episode.id)?;
+        push_media_info(&mut tag, episode.duration);
+
+        self.push_cover(&mut tag, &episode.id, &episode.covers, filepath).await?;
+
+        Ok(tag)
+    }
+
+    /// Adds the open.spotify.com link of `uri` to `tag` with `config.embed_url`, in its own
+    /// field so comments stay free for the user.
+    fn push_url(&self, tag: &mut Tag, uri: &SpotifyUri) -> Result<(), Error> {
+        if self.config.embed_url {
+            let url_key = custom_key(tag.tag_type(), SPOTIFY_URL_KEY);
+            tag.insert_unchecked(TagItem::new(url_key, ItemValue::Text(spotify_url(uri)?)));
+        }
+        Ok(())
+    }
+
+    /// Embeds the cover into `tag` and, with `config.save_cover`, also saves it next to
+    /// `filepath` unless this run already saved a cover there. The saved cover is always the
+    /// original, the embedded one is shrunk with `config.cover_max_size`.
+    async fn push_cover(
+        &self,
+        tag: &mut Tag,
+        uri: &SpotifyUri,
+        covers: &Images,
+        filepath: &Path
+    ) -> Result<(), Error> {
+        match self.get_cover(covers).await? {
+            Some((cover_data, mime_type)) => {
+                if self.config.save_cover {
+                    if let Some(dirpath) = filepath.parent() {
+                        if self.cover_dirs.lock().await.insert(dirpath.to_path_buf()) {
+                            save_cover_file(&cover_data, &mime_type, dirpath);
+                        }
+                    }
+                }
+                let (cover_data, mime_type) = self.embedded_cover(uri, cover_data, mime_type).await;
+                let picture = Picture::new_unchecked(
+                    PictureType::CoverFront,
+                    Some(mime_type),
+                    Some("cover".to_string()),
+                    cover_data
+                );
+                tag.push_picture(picture);
+            }
+            None => warn!("<{}> has no cover art, skipping embedded picture", uri),
+        }
+        Ok(())
+    }
+
+    /// The cover to embed: `cover_data` shrunk with `config.cover_max_size`, and converted
+    /// to JPEG when it is neither JPEG nor PNG, which is all many players show. The original
+    /// is kept when it can't be re-encoded.
+    async fn embedded_cover(&self, uri: &SpotifyUri, cover_data: Vec<u8>, mime_type: MimeType) -> Cover {
+        let max_size = self.config.cover_max_size;
+        let supported = matches!(mime_type, MimeType::Jpeg | MimeType::Png);
+        if max_size.is_none() && supported {
+            return (cover_data, mime_type);
+        }
+        if !supported {
+            debug!("<{}> converting the {} cover to JPEG", uri, mime_type);
+        }
+        let quality = self.config.cover_quality;
+        let original = cover_data.clone();
+        let reencoded = task::spawn_blocking(move || reencode_cover(&cover_data, max_size, quality)).await;
+        match reencoded.map_err(Error::internal).and_then(|reencoded| reencoded) {
+            Ok(jpeg) => (jpeg, MimeType::Jpeg),
+            Err(e) => {
+                warn!("<{}> unable to re-encode the {} cover, embedding the original: {e}", uri, mime_type);
+                (original, mime_type)
+            }
+        }
+    }
+
+    async fn get_cover(&self, covers: &Images) -> Result<Option<Cover>, Error> {
+        fn size_rank(size: image::ImageSize) -> i32 {
+            match size {
+                image::ImageSize::DEFAULT => 0,
+                image::ImageSize::SMALL => 1,
+                image::ImageSize::LARGE => 2,
+                image::ImageSize::XLARGE => 3,
+            }
+        }
+        // Prefer the requested size, then the next larger one, then the largest smaller one
+        let wanted = size_rank(self.config.cover_size);
+        let Some(cover) = covers
+            .iter()
+            .filter(|cover| size_rank(cover.size) >= wanted)
+            .min_by_key(|cover| size_rank(cover.size))
+            .or_else(|| covers.iter().max_by_key(|cover| size_rank(cover.size)))
+        else {
+            return Ok(None);
+        };
+        let cover_id = cover.id.to_string();
+        let cell = self.album_cover_cache.lock().await.entry(cover_id.clone()).or_default().clone();
+        let cover = cell.get_or_try_init(|| self.download_cover(&cover_id)).await?;
+        Ok(Some(cover.clone()))
+    }
+
+    async fn download_cover(&self, id: &String) -> Result<Cover, Error> {
+        let cached = self.config.cover_cache.as_ref().map(|dirpath| dirpath.join(id));
+        let cover_data = match cached.as_deref().map(std::fs::read) {
+            Some(Ok(cover_data)) if !cover_data.is_empty() => {
+                debug!("Cover {id} loaded from the cache");
+                cover_data
+            }
+            _ => {
+                let cover_data = self.retry("cover", || self.fetch_cover(id)).await?.to_vec();
+                if let Some(path) = &cached {
+                    cache_cover(path, &cover_data);
+                }
+                cover_data
+            }
+        };
+        let mime_type = infer::get(&cover_data)
+            .map(|t| MimeType::from_str(t.mime_type()))
+            .unwrap_or(MimeType::Jpeg);
+        Ok((cover_data, mime_type))
+    }
+
+    async fn fetch_cover(&self, id: &String) -> Result<Bytes, Error> {
+        let mut request = Request::builder()
+            .method(&Method::GET)
+            .uri(format!("{}{}", IMAGE_URL, id))
+            // No AVIF, which can't be converted for players that only show JPEG and PNG
+            .header(ACCEPT, HeaderValue::from_static("image/webp,image/apng,image/*,*/*;q=0.8"))
+            .header(USER_AGENT, HeaderValue::from_static(COVER_USER_AGENT))
+            .body(Full::new(Bytes::new()))?;
+        for (name, value) in &self.config.cover_headers {
+            request.headers_mut().insert(name, value.clone());
+        }
+        let response = self.cover_client()?.request(request).await?;
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            if let Some(delay) = HttpClient::get_retry_after(response.headers()) {
+                return Err(Error::resource_exhausted(RateLimited(delay)));
+            }
+        }
+        if !status.is_success() {
+            return Err(HttpClientError::StatusCode(status).into());
+        }
+        Ok(response.into_body().collect().await?.to_bytes())
+    }
+
+    /// Client covers are fetched with, going through the proxy of the session if it has one
+    fn cover_client(&self) -> Result<&CoverClient, Error> {
+        if let Some(client) = self.cover_client.get() {
+            return Ok(client);
+        }
+        let proxy = match &self.session().config().proxy {
+            Some(proxy_url) => Proxy::new(Intercept::All, proxy_url.as_str().parse()?),
+            None => Proxy::new(Intercept::None, http::Uri::from_static("0.0.0.0")),
+        };
+        let connector = ProxyConnector::from_proxy(HttpsConnector::new(), proxy)?;
+        let client = Client::builder(TokioExecutor::new()).build(connector);
+        Ok(self.cover_client.get_or_init(|| client))
+    }
+
+    /// Genres of `artist`, looked up once per run. The metadata service doesn't carry genres, so
+    /// they come from the Web API. Lookup failures are logged and treated as no genres.
+    async fn get_genres(&self, artist: &SpotifyUri) -> Vec<String> {
+        if self.config.genre_count == 0 {
+            return Vec::new();
+        }
+        if let Some(genres) = self.artist_genre_cache.lock().await.get(artist) {
+            return genres.clone();
+        }
+        let genres = match self.retry("genres", || self.fetch_genres(artist)).await {
+            Ok(genres) => genres,
+            Err(e) => {
+                warn!("<{}> unable to load genres: {e}", artist);
+                Vec::new()
+            }
+        };
+        self.artist_genre_cache.lock().await.insert(artist.clone(), genres.clone());
+        genres
+    }
+
+    async fn fetch_genres(&self, artist: &SpotifyUri) -> Result<Vec<String>, Error> {
+        let artist = self.fetch_web_api(&format!("{}{}", ARTIST_API_URL, artist.to_id()?)).await?;
+        Ok(artist["genres"]
+            .as_array()
+            .map(|genres| genres.iter().filter_map(|genre| genre.as_str()).map(str::to_string).collect())
+            .unwrap_or_default())
+    }
+
+    /// JSON response of the Web API endpoint `url`
+    async fn fetch_web_api(&self, url: &str) -> Result<serde_json::Value, Error> {
+        let token = self.session().login5().auth_token().await?;
+        let request = Request::builder()
+            .method(&Method::GET)
+            .uri(url)
+            .header(AUTHORIZATION, format!("Bearer {}", token.access_token))
+            .header(ACCEPT, HeaderValue::from_static("application/json"))
+            .body(Bytes::new())?;
+        let response = self.session().http_client().request_fut(request)?.await?;
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS {
+            if let Some(delay) = HttpClient::get_retry_after(response.headers()) {
+                return Err(Error::resource_exhausted(RateLimited(delay)));
+            }
+        }
+        if !status.is_success() {
+            return Err(HttpClientError::StatusCode(status).into());
+        }
+        let body = response.into_body().collect().await?.to_bytes();
+        serde_json::from_slice(&body).map_err(Error::failed_precondition)
+    }
+
+    /// Writer and producer credits of `track`, looked up once per run unless `config.credits`
+    /// is off. Tracks without credits get none.
+    async fn get_credits(&self, track: &SpotifyUri) -> Credits {
+        if !self.config.credits {
+            return Credits::default();
+        }
+        if let Some(credits) = self.track_credits_cache.lock().await.get(track) {
+            return credits.clone();
+        }
+        let credits = match self.retry("credits", || self.fetch_credits(track)).await {
+            Ok(credits) => credits,
+            Err(e) => {
+                debug!("<{}> has no credits: {e}", track);
+                Credits::default()
+            }
+        };
+        self.track_credits_cache.lock().await.insert(track.clone(), credits.clone());
+        credits
+    }
+
+    async fn fetch_credits(&self, track: &SpotifyUri) -> Result<Credits, Error> {
+        let endpoint = format!("/track-credits-view/v0/experimental/{}/credits", track.to_id()?);
+        let body = self.session().spclient().request_as_json(&Method::GET, &endpoint, None, None).await?;
+        let view: serde_json::Value = serde_json::from_slice(&body).map_err(Error::failed_precondition)?;
+        Ok(Credits::parse(&view))
+    }
+
+    /// Runs `operation` until it succeeds, fails permanently or `config.retry_attempts` is
+    /// exhausted, backing off exponentially between attempts. A server-provided `Retry-After`
+    /// takes precedence over the computed backoff. Each attempt is limited to
+    /// `config.request_timeout`, and to what is left until the `TRACK_DEADLINE`.
+    async fn retry<T, F, Fut>(&self, what: &str, mut operation: F) -> Result<T, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let timeout = match TRACK_DEADLINE.try_with(|deadline| *deadline) {
+                Ok(deadline) => self.config.request_timeout.min(deadline.saturating_duration_since(Instant::now())),
+                Err(_) => self.config.request_timeout,
+            };
+            let result = tokio::time::timeout(timeout, operation()).await.unwrap_or_else(|_| {
+                Err(Error::deadline_exceeded(format!("no response within {} ms", timeout.as_millis())))
+            });
+            match result {
+                Err(e) if attempt < self.config.retry_attempts && is_transient(&e) => {
+                    let delay = match e.error.downcast_ref::<RateLimited>() {
+                        Some(RateLimited(delay)) => *delay,
+                        None => backoff_delay(self.config.retry_base_delay, attempt),
+                    };
+                    warn!(
+                        "Unable to load {what} (attempt {attempt}/{}), retrying in {} ms: {e}",
+                        self.config.retry_attempts,
+                        delay.as_millis()
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use librespot::{core::config::SessionConfig, protocol::metadata};
+
+    /// Track metadata as Spotify sends it, crediting `artists` and without covers
+    fn track_fixture(name: &str, artists: &[&str]) -> Track {
+        Track::try_from(&track_message(name, artists)).unwrap()
+    }
+
+    fn track_message(name: &str, artists: &[&str]) -> metadata::Track {
+        let mut message = metadata::Track::new();
+        message.set_gid(vec![1; 16]);
+        message.set_name(name.to_string());
+        message.album.mut_or_insert_default().set_gid(vec![2; 16]);
+        for (index, name) in artists.iter().enumerate() {
+            let mut artist = metadata::Artist::new();
+            artist.set_gid(vec![3 + index as u8; 16]);
+            artist.set_name(name.to_string());
+            message.artist.push(artist);
+        }
+        message
+    }
+
+    /// Downloader whose tags need no requests: without genres, credits or progress bars
+    fn offline_downloader() -> Downloader {
+        let config = DownloaderConfig { genre_count: 0, show_progress: false, ..DownloaderConfig::default() };
+        Downloader::with_config(Session::new(SessionConfig::default(), None), config)
+    }
+
+    #[test]
+    fn subfile_reads_from_offset_up_to_length() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut subfile = Subfile::new(Cursor::new(data), 16, 8).unwrap();
+        let mut read = Vec::new();
+        subfile.read_to_end(&mut read).unwrap();
+        assert_eq!(read, (16..24).collect::<Vec<u8>>());
+        assert_eq!(subfile.read(&mut [0; 4]).unwrap(), 0);
+    }
+
+    #[test]
+    fn subfile_seeks_relative_to_its_own_end() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut subfile = Subfile::new(Cursor::new(data), 8, 32).unwrap();
+        assert_eq!(subfile.seek(SeekFrom::End(-16)).unwrap(), 16);
+        let mut byte = [0];
+        subfile.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], 24);
+    }
+
+    #[test]
+    fn subfile_refuses_seeking_before_its_start() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut subfile = Subfile::new(Cursor::new(data), 8, 32).unwrap();
+        assert!(subfile.seek(SeekFrom::End(-33)).is_err());
+        assert!(subfile.seek(SeekFrom::Current(-1)).is_err());
+        assert_eq!(subfile.stream_position().unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn tags_tracks_without_covers() {
+        let downloader = offline_downloader();
+        let track = track_fixture("Song", &[]);
+        assert!(track.album.covers.is_empty());
+        let tag = downloader
+            .track_tag("flac".to_string(), &track, &track.album, None, None, Path::new("Song.flac"))
+            .await
+            .unwrap();
+        assert!(tag.pictures().is_empty());
+        assert_eq!(tag.get_string(&ItemKey::TrackTitle), Some("Song"));
+    }
+
+    #[test]
+    fn parses_spotify_references() {
+        let album = SpotifyUri::from_uri("spotify:album:4aawyAB9vmqN3uQ7FjRGTy").unwrap();
+        let track = SpotifyUri::from_uri("spotify:track:6rqhFgbbKwnb9MLmUQDhG6").unwrap();
+        let references = [
+            ("https://open.spotify.com/album/4aawyAB9vmqN3uQ7FjRGTy?si=abc123", &album),
+            ("https://open.spotify.com/intl-de/track/6rqhFgbbKwnb9MLmUQDhG6", &track),
+            ("open.spotify.com/track/6rqhFgbbKwnb9MLmUQDhG6#fragment", &track),
+            ("spotify:track:6rqhFgbbKwnb9MLmUQDhG6", &track),
+            (" 4aawyAB9vmqN3uQ7FjRGTy ", &album),
+        ];
+        for (reference, uri) in references {
+            assert_eq!(&parse_reference(reference).unwrap(), uri, "{reference}");
+        }
+    }
+
+    #[test]
+    fn rejects_other_references() {
+        let references = [
+            "https://example.com/album/4aawyAB9vmqN3uQ7FjRGTy",
+            "https://open.spotify.com/album/4aawyAB9vmqN3uQ7FjRG",
+            "https://open.spotify.com/album",
+            "4aawyAB9vmqN3uQ7FjRGTy4aawy",
+            "spotify:local:artist:album:title:120",
+            "spotify:bogus:4aawyAB9vmqN3uQ7FjRGTy",
+        ];
+        for reference in references {
+            assert!(parse_reference(reference).is_err(), "{reference}");
+        }
+    }
+
+    #[test]
+    fn sanitizes_filenames() {
+        let names = [
+            ("AC/DC", "flac", "AC_DC.flac"),
+            ("Will you?", "ogg", "Will you_.ogg"),
+            ("con", "mp3", "con_.mp3"),
+            ("Nul.live", "mp3", "Nul.live_.mp3"),
+            ("Ends with dots...", "", "Ends with dots"),
+            ("  spaced   out  ", "", "spaced out"),
+        ];
+        for (name, extension, sanitized) in names {
+            assert_eq!(sanitize_filename(name, extension), sanitized, "{name}");
+        }
+    }
+
+    #[tokio::test]
+    async fn tags_isrcs_in_upper_case() {
+        let mut message = track_message("Song", &[]);
+        let mut isrc = metadata::ExternalId::new();
+        isrc.set_type("isrc".to_string());
+        isrc.set_id("usrc17607839".to_string());
+        message.external_id.push(isrc);
+        let track = Track::try_from(&message).unwrap();
+        let tag = offline_downloader()
+            .track_tag("flac".to_string(), &track, &track.album, None, None, Path::new("Song.flac"))
+            .await
+            .unwrap();
+        let tagged = tag.get_string(&ItemKey::Isrc).unwrap();
+        assert_eq!(tagged, "USRC17607839");
+        assert!(tagged[..2].chars().all(|c| c.is_ascii_uppercase()));
+    }
+
+    #[test]
+    fn maps_every_format_to_its_extension() {
+        let formats = [
+            (AudioFileFormat::OGG_VORBIS_96, "ogg"),
+            (AudioFileFormat::OGG_VORBIS_160, "ogg"),
+            (AudioFileFormat::OGG_VORBIS_320, "ogg"),
+            (AudioFileFormat::MP3_96, "mp3"),
+            (AudioFileFormat::MP3_160, "mp3"),
+            (AudioFileFormat::MP3_160_ENC, "mp3"),
+            (AudioFileFormat::MP3_256, "mp3"),
+            (AudioFileFormat::MP3_320, "mp3"),
+            (AudioFileFormat::AAC_24, "m4a"),
+            (AudioFileFormat::AAC_48, "m4a"),
+            (AudioFileFormat::AAC_160, "m4a"),
+            (AudioFileFormat::AAC_320, "m4a"),
+            (AudioFileFormat::MP4_128, "m4a"),
+            (AudioFileFormat::XHE_AAC_12, "m4a"),
+            (AudioFileFormat::XHE_AAC_16, "m4a"),
+            (AudioFileFormat::XHE_AAC_24, "m4a"),
+            (AudioFileFormat::FLAC_FLAC, "flac"),
+            (AudioFileFormat::FLAC_FLAC_24BIT, "flac"),
+            (AudioFileFormat::OTHER5, "bin"),
+        ];
+        for (format, extension) in formats {
+            assert_eq!(get_extension_from_format(format), extension, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn joins_featured_artists() {
+        // B and C are featured, only C is named in the title
+        let mut message = track_message("Song (feat. C)", &["A", "B", "C"]);
+        for index in [1, 2] {
+            let mut featured = metadata::ArtistWithRole::new();
+            featured.set_artist_gid(message.artist[index].gid().to_vec());
+            featured.set_artist_name(message.artist[index].name().to_string());
+            featured.set_role(metadata::artist_with_role::ArtistRole::ARTIST_ROLE_FEATURED_ARTIST);
+            message.artist_with_role.push(featured);
+        }
+        let track = Track::try_from(&message).unwrap();
+        assert_eq!(join_artists(&track, " & ", FeaturedArtists::Keep), "A & B & C");
+        assert_eq!(join_artists(&track, " & ", FeaturedArtists::Omit), "A");
+        assert_eq!(join_artists(&track, " & ", FeaturedArtists::OmitNamed), "A & B");
+    }
+
+    /// Decrypted Ogg download with Spotify's header page, then the Vorbis stream at `offset`
+    fn ogg_fixture(offset: usize) -> Vec<u8> {
+        let mut data = vec![0; offset];
+        data[..4].copy_from_slice(OGG_MAGIC);
+        data.extend_from_slice(OGG_MAGIC);
+        data.extend_from_slice(&[0, OGG_BEGINNING_OF_STREAM]);
+        data.resize(offset + 512, 0);
+        data
+    }
+
+    #[test]
+    fn finds_the_vorbis_stream_after_the_spotify_header() {
+        let mut data = Cursor::new(ogg_fixture(SPOTIFY_OGG_HEADER_END as usize));
+        assert_eq!(ogg_offset(&mut data).unwrap(), Some(SPOTIFY_OGG_HEADER_END));
+        let mut data = Cursor::new(ogg_fixture(0xc4));
+        assert_eq!(ogg_offset(&mut data).unwrap(), Some(0xc4));
+        let mut data = Cursor::new(vec![0; 1024]);
+        assert_eq!(ogg_offset(&mut data).unwrap(), None);
+    }
+
+    fn album_fixture(name: &str) -> Album {
+        let mut message = metadata::Album::new();
+        message.set_gid(vec![2; 16]);
+        message.set_name(name.to_string());
+        Album::try_from(&message).unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_albums_without_tracks() {
+        let downloader = offline_downloader();
+        let album = album_fixture("Empty");
+        let result = downloader.download_album(album.clone(), "unused").await;
+        assert!(matches!(result, Err(DownloadError::Empty(uri)) if uri == album.id));
+        assert!(!downloader.any_downloadable(&album.id, Path::new("unused/Empty"), &[]));
+    }
+
+    #[test]
+    fn parses_cover_headers() {
+        let (name, value) = parse_header("User-Agent: Mozilla/5.0 (X11)").unwrap();
+        assert_eq!(name, USER_AGENT);
+        assert_eq!(value, "Mozilla/5.0 (X11)");
+        assert!(parse_header("User-Agent").is_err());
+    }
+
+    #[tokio::test]
+    async fn times_out_stalled_downloads_and_removes_their_partial_file() {
+        let mut downloader = offline_downloader();
+        downloader.config.track_timeout = Some(Duration::from_millis(10));
+        let partpath = std::env::temp_dir().join(format!("stalled-{}.flac.part", std::process::id()));
+        let (copy_tx, copy_rx) = std::sync::mpsc::channel();
+        let stalled = async {
+            write(&partpath, b"fLaC")?;
+            // A blocking copy that only notices the timeout once its read returns
+            let (stop, copying) = TRACK_SCOPE.with(|scope| {
+                scope.partial.replace(Some(partpath.clone()));
+                let copying = CancellationToken::new();
+                scope.copying.replace(Some(copying.clone()));
+                (scope.stop.clone(), copying)
+            });
+            let partpath = partpath.clone();
+            std::thread::spawn(move || {
+                let _copying = copying.drop_guard();
+                while !stop.is_cancelled() {
+                    std::thread::sleep(Duration::from_millis(5));
+                }
+                std::thread::sleep(Duration::from_millis(20));
+                _ = copy_tx.send(partpath.exists());
+            });
+            futures::future::pending().await
+        };
+        let result = downloader.with_deadline(stalled).await;
+        assert!(matches!(result, Err(DownloadError::TimedOut)));
+        assert_eq!(copy_rx.recv().ok(), Some(true), "the partial file was removed while it was being copied");
+        assert!(!partpath.exists());
+    }
+
+    #[cfg(feature = "pcm")]
+    #[test]
+    fn pads_odd_aiff_sound_chunks() {
+        let mut aiff = Vec::new();
+        write_aiff(&mut aiff, 1, 44100, 24, 3, [1, 2, 3].into_iter().map(Ok)).unwrap();
+        // FORM header, COMM chunk, SSND header and 9 bytes of samples plus the pad byte
+        assert_eq!(aiff.len(), 12 + 26 + 16 + 9 + 1);
+        assert_eq!(aiff[4..8], ((aiff.len() - 8) as u32).to_be_bytes());
+        assert_eq!(aiff.last(), Some(&0));
+        assert!(write_aiff(&mut Vec::new(), 1, 44100, 24, 4, [1, 2, 3].into_iter().map(Ok)).is_err());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[tokio::test]
+    async fn downloads_indexed_tracks_again_once_deleted() {
+        let dirpath = std::env::temp_dir().join(format!("index-{}", std::process::id()));
+        create_dir_all(&dirpath).unwrap();
+        let index = TrackIndex::open(&dirpath.join("index.sqlite")).unwrap();
+        let uri = SpotifyUri::Track { id: SpotifyId::from_raw(&[1; 16]).unwrap() };
+        let indexed = dirpath.join("Old.flac");
+        let file = SavedFile {
+            path: indexed.clone(),
+            format: AudioFileFormat::FLAC_FLAC,
+            tagged: true,
+            title: String::new(),
+            duration: Duration::ZERO,
+            below_min_quality: false,
+            on_existing: None,
+        };
+        index.record(&uri, None, &file).unwrap();
+        let config = DownloaderConfig { index: Some(index), ..DownloaderConfig::default() };
+        let downloader = Downloader::with_config(Session::new(SessionConfig::default(), None), config);
+        let planned = dirpath.join("New.flac");
+        assert_eq!(downloader.resolve_existing(&uri, planned.clone()).unwrap().0, planned);
+        write(&indexed, b"fLaC").unwrap();
+        assert_eq!(downloader.resolve_existing(&uri, planned).unwrap_err(), indexed);
+        _ = std::fs::remove_dir_all(&dirpath);
+    }
+}
@@ -1,375 +1,1406 @@
 use std::{
-    collections::HashMap,
-    fs::{File, create_dir_all},
-    io::{self, Read, Seek, SeekFrom, copy},
-    path::PathBuf,
-    process::exit
+    fs::{
+        File, OpenOptions, TryLockError, create_dir_all, read_dir, read_to_string, remove_dir_all, remove_file, rename,
+        write
+    },
+    io::{self, IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH}
 };
 use librespot::{
-    core::{
-        Error, authentication::Credentials, cache::Cache, config::SessionConfig, session::Session,
-        SpotifyId, SpotifyUri
-    },
-    audio::{AudioDecrypt, AudioFile},
-    metadata::{
-        Album, Metadata, Track, image,
-        audio::{AudioFileFormat, AudioFiles},
-    },
-    oauth::OAuthClientBuilder
+    core::{Error, SpotifyUri, authentication::Credentials, cache::Cache, config::SessionConfig, session::Session},
+    metadata::{audio::AudioFileFormat, image},
+    oauth::{OAuthClient, OAuthClientBuilder, OAuthToken}
 };
-use log::{LevelFilter, debug, error, info, warn};
-use lofty::{
-    config::WriteOptions,
-    picture::{MimeType, Picture, PictureType},
-    prelude::*,
-    tag::{ItemKey, ItemValue, Tag, TagItem, TagType}
+use librespot_downloader::{
+    DEFAULT_ALBUM_ARTIST_SEPARATOR, DEFAULT_ARTIST_SEPARATOR, DEFAULT_CONCURRENCY, DEFAULT_COVER_QUALITY,
+    DEFAULT_FILENAME_TEMPLATE, DEFAULT_RELEASE_TYPES, DEFAULT_REQUEST_TIMEOUT, DEFAULT_RETRY_ATTEMPTS,
+    DEFAULT_RETRY_BASE_DELAY, FORMAT_PREFERENCE,
+    DownloadError, Downloader, DownloaderConfig, FeaturedArtists, LyricsMode, OnExisting, Prefetch, ReleaseType, ResumeState,
+    TrackOutcome, Variant,
+    parse_cover_size, parse_format, parse_header, parse_reference, remove_partial_files, sanitize_filename, write_manifest
 };
-use http::{HeaderValue, Method, Request, header::ACCEPT};
-use bytes::Bytes;
+#[cfg(feature = "transcode")]
+use librespot_downloader::{Transcode, TranscodeFormat};
+#[cfg(feature = "sqlite")]
+use librespot_downloader::TrackIndex;
+#[cfg(feature = "pcm")]
+use librespot_downloader::PcmFormat;
+use log::{LevelFilter, debug, error, info, warn};
+use tokio_util::sync::CancellationToken;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, parser::ValueSource};
+use serde::{Deserialize, Serialize};
+use indicatif::{HumanBytes, HumanDuration};
+use url::Url;
+use http::{HeaderName, HeaderValue};
 
 const CACHE: &str = ".cache";
-const CACHE_FILES: &str = ".cache/files";
-const SPOTIFY_OGG_HEADER_END: u64 = 0xa7;
-const IMAGE_URL: &str = "https://i.scdn.co/image/";
-const FORMAT_PREFERENCE: [AudioFileFormat; 19] = [
-    AudioFileFormat::FLAC_FLAC_24BIT,   // 1. Lossless, 24-bit high resolution
-    AudioFileFormat::FLAC_FLAC,         // 2. Standard lossless FLAC
-    AudioFileFormat::AAC_320,           // 3. High-bitrate AAC (excellent perceptual quality)
-    AudioFileFormat::MP3_320,           // 4. Highest-bitrate MP3 (widely compatible)
-    AudioFileFormat::MP3_256,           // 5. Mid-high MP3 bitrate
-    AudioFileFormat::OGG_VORBIS_320,    // 6. High-quality Vorbis (slightly less efficient than AAC)
-    AudioFileFormat::AAC_160,           // 7. Medium-bitrate AAC
-    AudioFileFormat::MP3_160_ENC,       // 8. Possibly a special encoder variant, quality similar to MP3_160
-    AudioFileFormat::MP3_160,           // 9. Standard MP3 midrange quality
-    AudioFileFormat::OGG_VORBIS_160,    // 10. Mid-bitrate Vorbis
-    AudioFileFormat::MP4_128,           // 11. Medium-low quality (likely AAC in MP4 container)
-    AudioFileFormat::AAC_48,            // 12. Low-quality AAC variant
-    AudioFileFormat::AAC_24,            // 13. Very low bitrate AAC
-    AudioFileFormat::XHE_AAC_24,        // 14. xHE-AAC at 24 kbps — better compression than plain AAC_24
-    AudioFileFormat::XHE_AAC_16,        // 15. Lower bitrate xHE-AAC
-    AudioFileFormat::XHE_AAC_12,        // 16. Minimal bitrate, speech quality only
-    AudioFileFormat::OGG_VORBIS_96,     // 17. Low-quality Vorbis
-    AudioFileFormat::MP3_96,            // 18. Low-quality MP3
-    AudioFileFormat::OTHER5,            // 19. Unknown/legacy format, last resort
-];
-
-fn get_extension_from_format(format: AudioFileFormat) -> String {
-    let extension = match format {
-        AudioFileFormat::OGG_VORBIS_96
-        | AudioFileFormat::OGG_VORBIS_160
-        | AudioFileFormat::OGG_VORBIS_320 => "ogg",
-        AudioFileFormat::MP3_96
-        | AudioFileFormat::MP3_160
-        | AudioFileFormat::MP3_256
-        | AudioFileFormat::MP3_320
-        | AudioFileFormat::MP3_160_ENC => "mp3",
-        AudioFileFormat::AAC_24
-        | AudioFileFormat::AAC_48
-        | AudioFileFormat::AAC_160
-        | AudioFileFormat::AAC_320
-        | AudioFileFormat::MP4_128
-        | AudioFileFormat::XHE_AAC_12
-        | AudioFileFormat::XHE_AAC_16
-        | AudioFileFormat::XHE_AAC_24 => "aac",
-        AudioFileFormat::FLAC_FLAC | AudioFileFormat::FLAC_FLAC_24BIT => "flac",
-        _ => "bin",
-    };
-    String::from(extension)
+/// Directories for each `--account` below the cache directory
+const ACCOUNTS_CACHE: &str = "accounts";
+const COVER_CACHE: &str = "covers";
+/// Audio files librespot caches below each session's cache directory
+const AUDIO_CACHE: &str = "files";
+/// Credentials librespot stores in each session's cache directory
+const CREDENTIALS_FILE: &str = "credentials.json";
+const CONFIG_FILE: &str = "librespot-downloader.toml";
+const OAUTH_TOKEN_FILE: &str = "oauth-token.json";
+const OAUTH_REDIRECT_URI: &str = "http://127.0.0.1:8898/login";
+/// Redirect URI without a port, so the resulting URL is pasted instead of being received
+const OAUTH_MANUAL_REDIRECT_URI: &str = "http://127.0.0.1/login";
+/// Lock file in the output directory held by the run writing into it
+const LOCK_FILE: &str = ".librespot-downloader.lock";
+/// Progress of the latest run in the output directory, see `ResumeState`
+const RESUME_FILE: &str = ".librespot-downloader.resume.json";
+/// Exit code of runs where some tracks or references failed while others were saved
+const EXIT_PARTIAL_FAILURE: i32 = 3;
+/// Exit code of runs unable to connect or log in
+const EXIT_AUTH_FAILURE: i32 = 4;
+/// Exit code of runs whose references resolved to no tracks at all
+const EXIT_NOTHING_RESOLVED: i32 = 5;
+/// Exit code of runs stopped with Ctrl+C, as shells report SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
+/// Size above which `--log-file` is moved aside to `<file>.1` before logging into it
+const LOG_FILE_LIMIT: u64 = 10 * 1024 * 1024;
+
+/// Settings read from `librespot-downloader.toml`, keyed like the command line flags. Flags
+/// given on the command line take precedence over the file, which takes precedence over the
+/// built-in defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+struct FileConfig {
+    output: Option<String>,
+    concurrency: Option<usize>,
+    max_rate: Option<u64>,
+    prefetch: Option<Prefetch>,
+    prefer: Option<Variant>,
+    cover_headers: Option<Vec<String>>,
+    track_timeout: Option<u64>,
+    request_timeout: Option<u64>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    quiet: Option<bool>,
+    overwrite: Option<bool>,
+    on_existing: Option<OnExisting>,
+    filename_template: Option<String>,
+    directory_template: Option<String>,
+    artist_separator: Option<String>,
+    featured_artists: Option<FeaturedArtists>,
+    album_artist_separator: Option<String>,
+    genres: Option<usize>,
+    lyrics: Option<LyricsMode>,
+    embed_lyrics: Option<bool>,
+    credits: Option<bool>,
+    extended_tags: Option<bool>,
+    dedup_isrc: Option<bool>,
+    verify: Option<bool>,
+    no_url: Option<bool>,
+    save_cover: Option<bool>,
+    save_metadata: Option<bool>,
+    nfo: Option<bool>,
+    cover_size: Option<String>,
+    cover_max_size: Option<u32>,
+    cover_quality: Option<u8>,
+    format: Option<Vec<String>>,
+    min_quality: Option<String>,
+    strict_quality: Option<bool>,
+    compilation: Option<bool>,
+    album_playlist: Option<bool>,
+    cue: Option<bool>,
+    playlist_as_album: Option<bool>,
+    include_types: Option<Vec<ReleaseType>>,
+    exclude_types: Option<Vec<ReleaseType>>,
+    manifest: Option<PathBuf>,
+    #[cfg(feature = "sqlite")]
+    index: Option<PathBuf>,
+    accounts: Option<Vec<String>>,
+    cache_dir: Option<PathBuf>,
+    no_cache: Option<bool>,
+    clean_partial: Option<bool>,
+    no_space_check: Option<bool>,
+    manual_login: Option<bool>,
+    wait_for_lock: Option<bool>,
+    proxy: Option<String>,
+    market: Option<String>,
+    fallback_markets: Option<Vec<String>>,
+}
+
+impl FileConfig {
+    /// Reads `path`, or else the first `CONFIG_FILE` found in the working directory or in
+    /// `$XDG_CONFIG_HOME` (`~/.config` when unset). Without any file the defaults are used.
+    /// Unknown keys are reported as warnings.
+    fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let path = match path {
+            Some(path) => path.to_path_buf(),
+            None => {
+                let config_home = std::env::var_os("XDG_CONFIG_HOME")
+                    .map(PathBuf::from)
+                    .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+                let candidates = [Some(PathBuf::from(CONFIG_FILE)), config_home.map(|dir| dir.join(CONFIG_FILE))];
+                match candidates.into_iter().flatten().find(|path| path.is_file()) {
+                    Some(path) => path,
+                    None => return Ok(Self::default()),
+                }
+            }
+        };
+        let text = std::fs::read_to_string(&path)?;
+        let invalid = |e: toml::de::Error| Error::invalid_argument(format!("{}: {e}", path.display()));
+        let deserializer = toml::Deserializer::parse(&text).map_err(invalid)?;
+        let config = serde_ignored::deserialize(deserializer, |key| {
+            warn!("Unknown key {key} in {}", path.display());
+        })
+        .map_err(invalid)?;
+        info!("Settings loaded from {:?}", path);
+        Ok(config)
+    }
+}
+
+/// `cli` when the flag `id` was given on the command line, otherwise `file`, falling back to
+/// `cli` which then holds the flag's default.
+fn merge<T>(matches: &ArgMatches, id: &str, cli: T, file: Option<T>) -> T {
+    match (matches.value_source(id), file) {
+        (Some(ValueSource::CommandLine), _) | (_, None) => cli,
+        (_, Some(file)) => file,
+    }
+}
+
+
+/// Download albums and tracks from Spotify
+#[derive(Parser)]
+#[command(
+    version,
+    about,
+    subcommand_negates_reqs = true,
+    after_help = "Exit codes: 0 success, 1 invalid arguments or configuration, 2 usage error, \
+        3 some tracks failed, 4 unable to connect or log in, 5 nothing resolved, 130 interrupted"
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Spotify references to download (open.spotify.com links, spotify: URIs or album ids)
+    #[arg(required_unless_present_any = ["liked", "input_file"])]
+    references: Vec<String>,
+
+    /// Also download the references listed in this file, one per line, or in stdin for `-`.
+    /// Blank lines and lines starting with # are ignored
+    #[arg(long)]
+    input_file: Option<PathBuf>,
+
+    /// Download the tracks saved in your Liked Songs
+    #[arg(long)]
+    liked: bool,
+
+    /// Most verbose level logged: off, error, warn, info, debug or trace. Logging starts before
+    /// the settings file is read, so this and --log-file are command line only
+    #[arg(long, default_value_t = LevelFilter::Debug)]
+    log_level: LevelFilter,
+
+    /// Also append the log to this file. A file over 10 MiB is renamed to <file>.1 first
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+
+    /// Format of log records
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Settings file to read instead of searching for librespot-downloader.toml
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Directory the downloads are saved into
+    #[arg(short, long, default_value = "downloads")]
+    output: String,
+
+    /// Number of tracks downloaded at the same time within an album or playlist
+    #[arg(short = 'j', long, default_value_t = DEFAULT_CONCURRENCY)]
+    concurrency: usize,
+
+    /// Limit the combined download speed of all tracks to this many bytes per second
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    max_rate: Option<u64>,
+
+    /// Whether each audio file is requested whole up front or fetched a few seconds ahead of
+    /// the download like during playback
+    #[arg(long, value_enum, default_value_t = Prefetch::Whole)]
+    prefetch: Prefetch,
+
+    /// Give up on a track or episode whose download takes longer than this many seconds
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    track_timeout: Option<u64>,
+
+    /// Seconds a single metadata, cover or audio key request may take before it is retried
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..), default_value_t = DEFAULT_REQUEST_TIMEOUT.as_secs())]
+    request_timeout: u64,
+
+    /// Times a failed metadata, cover, audio file or audio key request is retried, 0 to give up
+    /// right away. Only network failures, timeouts and rate limits are retried.
+    #[arg(long, default_value_t = DEFAULT_RETRY_ATTEMPTS - 1)]
+    retries: u32,
+
+    /// Milliseconds to wait before the first retry. The delay doubles with every further retry
+    /// and gets up to half of it added at random, unless Spotify asks to wait for a given time
+    #[arg(long, default_value_t = DEFAULT_RETRY_BASE_DELAY.as_millis() as u64)]
+    retry_delay: u64,
+
+    /// Account to download with, repeat to take turns between several accounts. Each account
+    /// keeps its credentials and audio cache in <cache dir>/accounts/<name>
+    #[arg(long = "account")]
+    accounts: Vec<String>,
+
+    /// Directory the credentials, OAuth token, audio files and covers are cached in
+    #[arg(long, default_value = CACHE)]
+    cache_dir: PathBuf,
+
+    /// Don't cache anything on disk. Without cached credentials every run logs in through OAuth
+    /// again, and dropped sessions reconnect with the credentials of the session they replace
+    #[arg(long, conflicts_with_all = ["cache_dir", "accounts"])]
+    no_cache: bool,
+
+    /// Remove partial downloads left in the output directory by interrupted runs before starting
+    #[arg(long)]
+    clean_partial: bool,
+
+    /// Don't refuse albums, playlists and shows whose estimated size exceeds the free disk
+    /// space. The estimate loads the metadata of every track before the download starts
+    #[arg(long)]
+    no_space_check: bool,
+
+    /// Log in without a browser on this machine: open the printed URL anywhere and paste the
+    /// address it redirects to
+    #[arg(long)]
+    manual_login: bool,
+
+    /// Wait for another run downloading into the same output directory to finish instead of
+    /// refusing to start
+    #[arg(long)]
+    wait_for_lock: bool,
+
+    /// Continue the interrupted previous run in the output directory: its remaining references
+    /// are downloaded after skipping the items it already saved, followed by any new references
+    #[arg(long)]
+    resume: bool,
+
+    /// Only add the cover art to existing downloads that have none, without downloading audio
+    /// or changing other tags. References may also be directories, whose files are matched to
+    /// tracks by the id in their name, their Spotify link or their ISRC
+    #[arg(long, conflicts_with_all = ["dry_run", "resume"])]
+    cover_only: bool,
+
+    /// HTTP proxy, e.g. http://proxy:3128, that the Spotify connection, metadata, audio, lyrics
+    /// and cover requests go through. The OAuth login doesn't use it, and proxies requiring
+    /// credentials aren't supported by librespot
+    #[arg(long, value_parser = parse_proxy)]
+    proxy: Option<Url>,
+
+    /// Extra `Name: value` header sent with cover requests, can be repeated. A `User-Agent` replaces
+    /// the browser-like default
+    #[arg(long = "cover-header", value_parser = parse_header)]
+    cover_headers: Vec<(HeaderName, HeaderValue)>,
+
+    /// List the tracks that would be downloaded with their format and path, without
+    /// downloading or writing anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Write the untagged audio of a single track or episode to stdout instead of saving it,
+    /// e.g. to pipe it into a player
+    #[arg(long, conflicts_with_all = ["liked", "input_file", "dry_run", "manifest"])]
+    stdout: bool,
+
+    /// Write a JSON record of every track handled in this run to this file
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+
+    /// SQLite database recording every download, tracks found in it are skipped wherever
+    /// their files are now. Created when missing
+    #[cfg(feature = "sqlite")]
+    #[arg(long)]
+    index: Option<PathBuf>,
+
+    /// Don't draw progress bars
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Download tracks again even if they already exist in the output directory, short for
+    /// --on-existing overwrite
+    #[arg(long, conflicts_with = "on_existing")]
+    overwrite: bool,
+
+    /// What to do when a track already exists in the output directory
+    #[arg(long, value_enum, default_value_t = OnExisting::Skip)]
+    on_existing: OnExisting,
+
+    /// Track filename template using {artist}, {album}, {title}, {track}, {disc}, {id} and
+    /// {year}, with optional zero-padding like {track:02}
+    #[arg(long, default_value = DEFAULT_FILENAME_TEMPLATE)]
+    filename_template: String,
+
+    /// Directory layout below the output directory using {album_artist}, {artist}, {album},
+    /// {disc} and {year}, e.g. "{album_artist}/{album} ({year})". An empty template saves all
+    /// files directly into the output directory
+    #[arg(long)]
+    directory_template: Option<String>,
+
+    /// Separator between multiple artists in the artist tag and the {artist} placeholder
+    #[arg(long, default_value = DEFAULT_ARTIST_SEPARATOR)]
+    artist_separator: String,
+
+    /// Which featured artists to credit in artist tags and filenames
+    #[arg(long, value_enum, default_value_t = FeaturedArtists::Keep)]
+    featured_artists: FeaturedArtists,
+
+    /// Separator between multiple album artists in the album artist tag
+    #[arg(long, default_value = DEFAULT_ALBUM_ARTIST_SEPARATOR)]
+    album_artist_separator: String,
+
+    /// Number of the primary artist's genres written to the genre tag, 0 disables genre tags
+    #[arg(long, default_value_t = 1)]
+    genres: usize,
+
+    /// Fetch lyrics and save them as a synced .lrc file or as plain text in the tags
+    #[arg(long, value_enum, default_value_t = LyricsMode::None)]
+    lyrics: LyricsMode,
+
+    /// Embed the lyrics text into the audio tags, in addition to what --lyrics does
+    #[arg(long)]
+    embed_lyrics: bool,
+
+    /// Tag composers, lyricists and producers from the track credits, one extra request per track
+    #[arg(long)]
+    credits: bool,
+
+    /// Tag the popularity of tracks and, for playlists and Liked Songs, when they were added,
+    /// as the non-standard SPOTIFY_POPULARITY and SPOTIFY_ADDED fields
+    #[arg(long)]
+    extended_tags: bool,
+
+    /// Skip tracks whose ISRC was already downloaded by this run, keeping the oldest release
+    /// of an artist
+    #[arg(long)]
+    dedup_isrc: bool,
+
+    /// Decode FLAC downloads after saving them to check they are intact, downloading a corrupt
+    /// one once more before counting it as failed
+    #[arg(long)]
+    verify: bool,
+
+    /// Don't embed the open.spotify.com link of each track into its tags
+    #[arg(long)]
+    no_url: bool,
+
+    /// Also save the cover art as cover.<ext> in each album directory
+    #[arg(long)]
+    save_cover: bool,
+
+    /// Also save the album and track metadata as album.json in each album directory
+    #[arg(long)]
+    save_metadata: bool,
+
+    /// Also write Kodi and Jellyfin NFO files for each album and track
+    #[arg(long)]
+    nfo: bool,
+
+    /// Also write an .m3u8 playlist for downloaded albums, shows and Liked Songs, playlists always
+    /// get one
+    #[arg(long)]
+    album_playlist: bool,
+
+    /// Also write a CUE sheet listing the tracks of each album into its directory, and one with
+    /// the offset of each track next to each file joined with --concat
+    #[arg(long)]
+    cue: bool,
+
+    /// Tag playlist tracks with the playlist name as album and their playlist position as
+    /// track number, so players sorting by track number keep the playlist order
+    #[arg(long)]
+    playlist_as_album: bool,
+
+    /// Comma-separated types of release downloaded of an artist
+    #[arg(long, value_enum, value_delimiter = ',', default_values_t = DEFAULT_RELEASE_TYPES)]
+    include_types: Vec<ReleaseType>,
+
+    /// Comma-separated types of release left out of an artist, even when included
+    #[arg(long, value_enum, value_delimiter = ',')]
+    exclude_types: Vec<ReleaseType>,
+
+    /// Mark every downloaded track as part of a compilation, even if the album doesn't look
+    /// like one
+    #[arg(long)]
+    compilation: bool,
+
+    /// Comma-separated audio formats to download in order of preference, e.g.
+    /// FLAC_FLAC,MP3_320. Tracks available in none of them are skipped
+    #[arg(long, value_parser = parse_format, value_delimiter = ',')]
+    format: Vec<AudioFileFormat>,
+
+    /// Lowest acceptable audio format, ranked by quality, e.g. OGG_VORBIS_320. Tracks only
+    /// available in worse formats are downloaded with a warning
+    #[arg(long, value_parser = parse_format)]
+    min_quality: Option<AudioFileFormat>,
+
+    /// Skip tracks below --min-quality instead of downloading them
+    #[arg(long, requires = "min_quality")]
+    strict_quality: bool,
+
+    /// Size of the cover art, the next larger size is used when it isn't available
+    #[arg(long, value_parser = parse_cover_size, default_value = "xlarge")]
+    cover_size: image::ImageSize,
+
+    /// Re-encode embedded covers as JPEG no wider or taller than this many pixels. Covers saved
+    /// with --save-cover stay original
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    cover_max_size: Option<u32>,
+
+    /// JPEG quality of covers re-encoded for --cover-max-size or converted from other formats
+    /// than JPEG and PNG
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), default_value_t = DEFAULT_COVER_QUALITY)]
+    cover_quality: u8,
+
+    /// Country code, e.g. DE, whose catalog restrictions decide which version of a track is
+    /// downloaded. Defaults to the account's country
+    #[arg(long)]
+    market: Option<String>,
+
+    /// Comma-separated country codes tried in order when a track is restricted in --market
+    #[arg(long = "fallback-market", value_delimiter = ',')]
+    fallback_markets: Vec<String>,
+
+    /// Download the explicit or the clean version of tracks that have both
+    #[arg(long, value_enum)]
+    prefer: Option<Variant>,
+
+    /// Also convert every downloaded track to this format with ffmpeg
+    #[cfg(feature = "transcode")]
+    #[arg(long, value_enum)]
+    transcode: Option<TranscodeFormat>,
+
+    /// Audio bitrate of transcoded tracks
+    #[cfg(feature = "transcode")]
+    #[arg(long, default_value = "320k", requires = "transcode")]
+    transcode_bitrate: String,
+
+    /// Remove the original file once it has been transcoded
+    #[cfg(feature = "transcode")]
+    #[arg(long, requires = "transcode")]
+    transcode_replace: bool,
+
+    /// Also join the tracks of playlists into one file named after the playlist with ffmpeg,
+    /// with a chapter per track. Only tracks saved in the same format are joined.
+    #[cfg(feature = "transcode")]
+    #[arg(long)]
+    concat: bool,
+
+    /// Also decode lossless downloads to uncompressed audio in this format
+    #[cfg(feature = "pcm")]
+    #[arg(long, value_enum)]
+    pcm: Option<PcmFormat>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the tracks of albums, playlists, artists, shows or single items along with the
+    /// formats they are available in, without downloading anything
+    Info {
+        /// Spotify references to describe
+        #[arg(required = true)]
+        references: Vec<String>,
+    },
+    /// Print every format the tracks or episodes of albums, playlists, shows or single items are
+    /// offered in, with the extension and data rate each is saved with and the one --format
+    /// selects
+    ListFormats {
+        /// Spotify references to list the formats of
+        #[arg(required = true)]
+        references: Vec<String>,
+    },
+    /// Rewrite the tags of already downloaded tracks and episodes from fresh metadata without
+    /// downloading the audio again. Files are found by the id in their name or in --index
+    Retag {
+        /// Spotify references whose downloads are retagged
+        #[arg(required = true)]
+        references: Vec<String>,
+    },
+    /// Inspect or clear the cache directory, see --cache-dir
+    Cache {
+        #[command(subcommand)]
+        action: CacheAction,
+    },
 }
 
-fn format_data_rate(format: AudioFileFormat) -> usize {
-    let kbps = match format {
-        AudioFileFormat::OGG_VORBIS_96 => 12.,
-        AudioFileFormat::OGG_VORBIS_160 => 20.,
-        AudioFileFormat::OGG_VORBIS_320 => 40.,
-        AudioFileFormat::MP3_256 => 32.,
-        AudioFileFormat::MP3_320 => 40.,
-        AudioFileFormat::MP3_160 => 20.,
-        AudioFileFormat::MP3_96 => 12.,
-        AudioFileFormat::MP3_160_ENC => 20.,
-        AudioFileFormat::AAC_24 => 3.,
-        AudioFileFormat::AAC_48 => 6.,
-        AudioFileFormat::AAC_160 => 20.,
-        AudioFileFormat::AAC_320 => 40.,
-        AudioFileFormat::MP4_128 => 16.,
-        AudioFileFormat::OTHER5 => 40.,
-        AudioFileFormat::FLAC_FLAC => 112., // assume 900 kbit/s on average
-        AudioFileFormat::XHE_AAC_12 => 1.5,
-        AudioFileFormat::XHE_AAC_16 => 2.,
-        AudioFileFormat::XHE_AAC_24 => 3.,
-        AudioFileFormat::FLAC_FLAC_24BIT => 3.,
+#[derive(Subcommand)]
+enum CacheAction {
+    /// Print how many files the cached audio, covers and credentials take up and their size
+    Info,
+    /// Remove the cached audio files and covers
+    Clear {
+        /// Also remove the cached credentials and OAuth tokens, so the next run logs in again
+        #[arg(long)]
+        credentials: bool,
+        /// Don't ask for confirmation
+        #[arg(long, short = 'y')]
+        yes: bool,
+    },
+}
+
+/// Format of log records, see `--log-format`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LogFormat {
+    /// Human-readable lines
+    Text,
+    /// One JSON object per line with timestamp, level, module and message, plus the id of the
+    /// item a message is about
+    Json,
+}
+
+/// Writes `record` as one line of JSON, see `LogFormat::Json`. Messages about an item start
+/// with its uri in angle brackets, which is lifted into an `id` field.
+fn write_json_record(buf: &mut env_logger::fmt::Formatter, record: &log::Record) -> io::Result<()> {
+    let message = record.args().to_string();
+    let id = message
+        .strip_prefix('<')
+        .and_then(|rest| rest.split_once('>'))
+        .map(|(id, _)| id)
+        .filter(|id| id.starts_with("spotify:"));
+    let json = serde_json::json!({
+        "timestamp": buf.timestamp().to_string(),
+        "level": record.level().as_str(),
+        "module": record.module_path(),
+        "id": id,
+        "message": message,
+    });
+    writeln!(buf, "{json}")
+}
+
+/// Passes log output on to stderr and to a log file
+struct Tee {
+    file: File,
+}
+
+impl Write for Tee {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::stderr().write_all(buf)?;
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()?;
+        self.file.flush()
+    }
+}
+
+/// Opens `path` for appending log output, first moving it to `<path>.1` when it exceeds
+/// `LOG_FILE_LIMIT`.
+fn open_log_file(path: &Path) -> io::Result<File> {
+    if path.metadata().is_ok_and(|metadata| metadata.len() > LOG_FILE_LIMIT) {
+        let mut rotated = path.as_os_str().to_owned();
+        rotated.push(".1");
+        rename(path, rotated)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Takes the lock of the output directory `output`, creating both when missing. Returns
+/// `None` without `wait` when another run holds it. The lock lasts as long as the file stays
+/// open, the system drops it when the process ends however it does.
+fn lock_output(output: &Path, wait: bool) -> io::Result<Option<File>> {
+    create_dir_all(output)?;
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(output.join(LOCK_FILE))?;
+    match file.try_lock() {
+        Ok(()) => return Ok(Some(file)),
+        Err(TryLockError::WouldBlock) if wait => {}
+        Err(TryLockError::WouldBlock) => return Ok(None),
+        Err(TryLockError::Error(e)) => return Err(e),
+    }
+    info!("Waiting for the other run downloading into {:?} to finish", output);
+    file.lock()?;
+    Ok(Some(file))
+}
+
+/// References listed in `path`, or on stdin for `-`, skipping blank lines and `#` comments
+fn read_references(path: &Path) -> io::Result<Vec<String>> {
+    let text = if path == Path::new("-") {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text)?;
+        text
+    } else {
+        read_to_string(path)?
     };
-    let data_rate: f32 = kbps * 1024.;
-    data_rate.ceil() as usize
+    let references = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect();
+    Ok(references)
 }
 
-struct Subfile<T: Read + Seek> {
-    stream: T,
-    offset: u64,
-    length: u64,
+/// OAuth token saved next to the cached credentials, so the browser login isn't needed again
+/// while the token can be refreshed
+#[derive(Serialize, Deserialize)]
+struct StoredToken {
+    access_token: String,
+    refresh_token: String,
+    /// Seconds since the Unix epoch
+    expires_at: u64,
 }
 
-impl<T: Read + Seek> Subfile<T> {
-    pub fn new(mut stream: T, offset: u64, length: u64) -> Result<Subfile<T>, io::Error> {
-        let target = SeekFrom::Start(offset);
-        stream.seek(target)?;
+impl StoredToken {
+    fn new(token: &OAuthToken) -> Self {
+        let remaining = token.expires_at.saturating_duration_since(Instant::now());
+        let expires_at = SystemTime::now() + remaining;
+        Self {
+            access_token: token.access_token.clone(),
+            refresh_token: token.refresh_token.clone(),
+            expires_at: expires_at.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()),
+        }
+    }
 
-        Ok(Subfile {
-            stream,
-            offset,
-            length,
-        })
+    fn load(path: &Path) -> Option<Self> {
+        let text = read_to_string(path).ok()?;
+        serde_json::from_str(&text).inspect_err(|e| warn!("Ignoring invalid {:?}: {e}", path)).ok()
+    }
+
+    fn save(&self, path: &Path) {
+        let saved = serde_json::to_vec(self).map_err(Error::internal).and_then(|json| Ok(write(path, json)?));
+        if let Err(e) = saved {
+            warn!("Unable to save the OAuth token to {:?}: {e}", path);
+        }
+    }
+
+    /// Whether the access token is still valid for at least another minute
+    fn is_fresh(&self) -> bool {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        now + Duration::from_secs(60) < Duration::from_secs(self.expires_at)
     }
 }
 
-impl<T: Read + Seek> Read for Subfile<T> {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.stream.read(buf)
+/// Access token from the token stored at `token_path`, refreshed when it expired, or else from
+/// a new login. Every new token is stored for the next run.
+fn oauth_access_token(client: &OAuthClient, token_path: &Path) -> Result<String, Error> {
+    if let Some(stored) = StoredToken::load(token_path) {
+        if stored.is_fresh() {
+            debug!("Using the stored OAuth token");
+            return Ok(stored.access_token);
+        }
+        match client.refresh_token(&stored.refresh_token) {
+            Ok(token) => {
+                info!("Refreshed the stored OAuth token");
+                StoredToken::new(&token).save(token_path);
+                return Ok(token.access_token);
+            }
+            Err(e) => warn!("Unable to refresh the stored OAuth token, logging in again: {e}"),
+        }
     }
+    let token = client.get_access_token()?;
+    StoredToken::new(&token).save(token_path);
+    Ok(token.access_token)
 }
 
-impl<T: Read + Seek> Seek for Subfile<T> {
-    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        let pos = match pos {
-            SeekFrom::Start(offset) => SeekFrom::Start(offset + self.offset),
-            SeekFrom::End(offset) => {
-                if (self.length as i64 - offset) < self.offset as i64 {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "newpos would be < self.offset",
-                    ));
-                }
-                pos
+/// Parses the `--proxy` URL. librespot tunnels through HTTP proxies with CONNECT only and
+/// sends no credentials, so other schemes and URLs with credentials are refused.
+fn parse_proxy(proxy: &str) -> Result<Url, String> {
+    let url = Url::parse(proxy).map_err(|e| format!("invalid proxy URL: {e}"))?;
+    if url.scheme() != "http" {
+        return Err(format!("unsupported proxy scheme {}, only http proxies are supported", url.scheme()));
+    }
+    if !url.username().is_empty() || url.password().is_some() {
+        return Err("proxies requiring credentials aren't supported".to_string());
+    }
+    if url.host().is_none() {
+        return Err("the proxy URL has no host".to_string());
+    }
+    Ok(url)
+}
+
+/// Number of files below `path` and their total size in bytes
+fn disk_usage(path: &Path) -> (u64, u64) {
+    let Ok(entries) = read_dir(path) else {
+        return (0, 0);
+    };
+    entries.filter_map(|entry| entry.ok()).fold((0, 0), |(files, bytes), entry| {
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => {
+                let (dir_files, dir_bytes) = disk_usage(&entry.path());
+                (files + dir_files, bytes + dir_bytes)
             }
-            _ => pos,
-        };
+            Ok(metadata) => (files + 1, bytes + metadata.len()),
+            Err(_) => (files, bytes),
+        }
+    })
+}
 
-        let newpos = self.stream.seek(pos)?;
-        Ok(newpos - self.offset)
+/// Session cache directories below `cache_dir`: its own and the one of each `--account`
+fn session_caches(cache_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = vec![cache_dir.to_path_buf()];
+    if let Ok(entries) = read_dir(cache_dir.join(ACCOUNTS_CACHE)) {
+        let mut accounts: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+            .map(|entry| entry.path())
+            .collect();
+        accounts.sort();
+        dirs.extend(accounts);
     }
+    dirs
 }
 
-pub struct Downloader {
-    pub session: Session,
-    album_cover_cache: HashMap<String, (Vec<u8>, MimeType)>,
+/// Prints the files cached in `cache_dir` per session and for covers, see `CacheAction::Info`
+fn print_cache_info(cache_dir: &Path) {
+    println!("Cache directory: {}", cache_dir.display());
+    let (total_files, total_bytes) = disk_usage(cache_dir);
+    for dir in session_caches(cache_dir) {
+        let (files, bytes) = disk_usage(&dir.join(AUDIO_CACHE));
+        let logged_in = dir.join(CREDENTIALS_FILE).is_file() || dir.join(OAUTH_TOKEN_FILE).is_file();
+        let name = dir.strip_prefix(cache_dir).ok().filter(|name| !name.as_os_str().is_empty());
+        println!(
+            "  {}: {files} audio files ({}), {}",
+            name.unwrap_or(Path::new("Default session")).display(),
+            HumanBytes(bytes),
+            if logged_in { "credentials cached" } else { "no credentials" }
+        );
+    }
+    let (files, bytes) = disk_usage(&cache_dir.join(COVER_CACHE));
+    println!("  Covers: {files} files ({})", HumanBytes(bytes));
+    println!("  Total: {total_files} files ({})", HumanBytes(total_bytes));
 }
 
-impl Downloader {
-    pub fn new(session: Session) -> Self {
-        Self {
-            session,
-            album_cover_cache: HashMap::new(),
+/// Removes the cached audio files and covers from `cache_dir`, along with the credentials and
+/// OAuth tokens when `credentials` is set. Returns how many files and bytes were freed.
+fn clear_cache(cache_dir: &Path, credentials: bool) -> io::Result<(u64, u64)> {
+    let mut dirs: Vec<PathBuf> = session_caches(cache_dir).iter().map(|dir| dir.join(AUDIO_CACHE)).collect();
+    dirs.push(cache_dir.join(COVER_CACHE));
+    let mut files = Vec::new();
+    if credentials {
+        for dir in session_caches(cache_dir) {
+            files.push(dir.join(CREDENTIALS_FILE));
+            files.push(dir.join(OAUTH_TOKEN_FILE));
         }
     }
 
-    pub async fn download_album_by_id(&mut self, base62: &str, directory: &str) -> Result<(), Error> {
-        let id = SpotifyId::from_base62(base62)?;
-        let uri = SpotifyUri::Album { id };
-        let album = Album::get(&self.session, &uri).await?;
-        self.download_album(album, directory).await
+    let (mut removed_files, mut removed_bytes) = (0, 0);
+    for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+        let (dir_files, dir_bytes) = disk_usage(dir);
+        remove_dir_all(dir)?;
+        removed_files += dir_files;
+        removed_bytes += dir_bytes;
     }
+    for file in files {
+        match file.metadata() {
+            Ok(metadata) => {
+                remove_file(&file)?;
+                removed_files += 1;
+                removed_bytes += metadata.len();
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((removed_files, removed_bytes))
+}
 
-    pub async fn download_album(&mut self, album: Album, directory: &str) -> Result<(), Error> {
-        info!("Downloading Album: {}", album.name);
-        let mut dirpath = PathBuf::from(directory);
-        dirpath.push(&album.name);
-        info!("<{}> saved at {:?}", album.id, dirpath);
-        _ = create_dir_all(&dirpath);
-        for track_uri in album.tracks() {
-            self.download_track_by_uri(&track_uri, &dirpath).await?;
-        };
-        Ok(())
+/// Asks `question` on the terminal, returning whether it was answered with yes. Without a
+/// terminal to ask on nothing is confirmed.
+fn confirm(question: &str) -> bool {
+    if !io::stdin().is_terminal() {
+        return false;
     }
+    eprint!("{question} [y/N] ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok_and(|_| matches!(answer.trim(), "y" | "Y" | "yes"))
+}
 
-    pub async fn download_track_by_uri(&mut self, uri: &SpotifyUri, dirpath: &PathBuf) -> Result<(), Error> {
-        let track = Track::get(&self.session, uri).await?;
-        self.download_track(&track, dirpath).await
+/// Connects a session with the credentials cached in `cache_dir`, logging in through OAuth
+/// when there are none yet or no `cache_dir` is given. The login opens a browser unless
+/// `manual_login` is set. Audio files are cached below `cache_dir` as well. All session traffic
+/// goes through `proxy` when given.
+async fn connect(cache_dir: Option<&Path>, manual_login: bool, proxy: Option<&Url>) -> Result<Session, Error> {
+    let session_config = SessionConfig { proxy: proxy.cloned(), ..SessionConfig::default() };
+
+    let cache = cache_dir
+        .map(|cache_dir| Cache::new(Some(cache_dir), Some(cache_dir), Some(&cache_dir.join("files")), None))
+        .transpose()?;
+    let credentials = match cache.as_ref().and_then(Cache::credentials) {
+        Some(credentials) => credentials,
+        None => {
+            let client = if manual_login {
+                OAuthClientBuilder::new(&session_config.client_id, OAUTH_MANUAL_REDIRECT_URI, vec!["streaming"])
+                    .build()?
+            } else {
+                OAuthClientBuilder::new(&session_config.client_id, OAUTH_REDIRECT_URI, vec!["streaming"])
+                    .open_in_browser()
+                    .build()?
+            };
+            let access_token = match cache_dir {
+                Some(cache_dir) => oauth_access_token(&client, &cache_dir.join(OAUTH_TOKEN_FILE))?,
+                None => client.get_access_token()?.access_token,
+            };
+            Credentials::with_access_token(access_token)
+        }
+    };
+
+    let session = Session::new(session_config, cache);
+    session.connect(credentials, true).await?;
+    Ok(session)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let started = Instant::now();
+
+    let mut logger = env_logger::builder();
+    logger.filter_module("librespot", cli.log_level);
+    if cli.log_format == LogFormat::Json {
+        logger.format(write_json_record);
     }
+    if let Some(log_file) = &cli.log_file {
+        match open_log_file(log_file) {
+            Ok(file) => {
+                logger.target(env_logger::Target::Pipe(Box::new(Tee { file })));
+            }
+            Err(e) => {
+                eprintln!("Unable to open the log file {:?}: {e}", log_file);
+                exit(1);
+            }
+        }
+    }
+    logger.init();
 
-    pub async fn download_track(&mut self, track: &Track, dirpath: &PathBuf) -> Result<(), Error> {
-        info!("Downloading Track #{}: {} ({})", track.number, track.name, track.id);
-        let track_id = match track.id {
-            SpotifyUri::Track { id } => id,
-            _ => return Ok(()),
+    let file = match FileConfig::load(cli.config.as_deref()) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("Invalid config file: {e}");
+            exit(1);
+        }
+    };
+    let file_cover_size = match file.cover_size.as_deref().map(parse_cover_size).transpose() {
+        Ok(cover_size) => cover_size,
+        Err(e) => {
+            error!("Invalid config file: {e}");
+            exit(1);
+        }
+    };
+    let file_format = match file.format.map(|names| names.iter().map(|name| parse_format(name)).collect()).transpose() {
+        Ok(format) => format,
+        Err(e) => {
+            error!("Invalid config file: {e}");
+            exit(1);
+        }
+    };
+    let file_proxy = match file.proxy.as_deref().map(parse_proxy).transpose() {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            error!("Invalid config file: {e}");
+            exit(1);
+        }
+    };
+    let proxy = merge(&matches, "proxy", cli.proxy, file_proxy.map(Some));
+    let file_cover_headers = file.cover_headers.as_ref().map(|headers| {
+        headers.iter().map(|header| parse_header(header)).collect::<Result<Vec<_>, _>>()
+    });
+    let file_cover_headers = match file_cover_headers.transpose() {
+        Ok(headers) => headers,
+        Err(e) => {
+            error!("Invalid config file: {e}");
+            exit(1);
+        }
+    };
+    let file_min_quality = match file.min_quality.as_deref().map(parse_format).transpose() {
+        Ok(format) => format,
+        Err(e) => {
+            error!("Invalid config file: {e}");
+            exit(1);
+        }
+    };
+    let format_preference = match merge(&matches, "format", cli.format, file_format) {
+        formats if formats.is_empty() => FORMAT_PREFERENCE.to_vec(),
+        formats => formats,
+    };
+    let output = merge(&matches, "output", cli.output, file.output);
+    let manifest = merge(&matches, "manifest", cli.manifest, file.manifest.map(Some));
+    let accounts = merge(&matches, "accounts", cli.accounts, file.accounts);
+    let cache_dir = match merge(&matches, "no_cache", cli.no_cache, file.no_cache) {
+        true => None,
+        false => Some(merge(&matches, "cache_dir", cli.cache_dir, file.cache_dir)),
+    };
+    if cache_dir.is_none() && !accounts.is_empty() {
+        error!("--account keeps each account's credentials in the cache, which --no-cache disables");
+        exit(1);
+    }
+    if let Some(Command::Cache { action }) = &cli.command {
+        let Some(cache_dir) = &cache_dir else {
+            error!("There is no cache to manage with --no-cache");
+            exit(1);
         };
+        match action {
+            CacheAction::Info => print_cache_info(cache_dir),
+            CacheAction::Clear { credentials, yes } => {
+                let what = if *credentials { "cached audio, covers and credentials" } else { "cached audio and covers" };
+                if !yes && !confirm(&format!("Remove the {what} in {}?", cache_dir.display())) {
+                    error!("Not clearing the cache without confirmation, pass --yes to skip it");
+                    exit(1);
+                }
+                match clear_cache(cache_dir, *credentials) {
+                    Ok((files, bytes)) => info!("Removed {files} files ({}) from {:?}", HumanBytes(bytes), cache_dir),
+                    Err(e) => {
+                        error!("Unable to clear the cache {:?}: {e}", cache_dir);
+                        exit(1);
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+    let quiet = merge(&matches, "quiet", cli.quiet, file.quiet);
+    let manual_login = merge(&matches, "manual_login", cli.manual_login, file.manual_login);
+    let writes_output = !cli.dry_run
+        && !cli.stdout
+        && !matches!(cli.command, Some(Command::Info { .. } | Command::ListFormats { .. }));
+    let wait_for_lock = merge(&matches, "wait_for_lock", cli.wait_for_lock, file.wait_for_lock);
+    let _lock = match writes_output.then(|| lock_output(Path::new(&output), wait_for_lock)).transpose() {
+        Ok(Some(None)) => {
+            error!("Another run is downloading into {:?}, pass --wait-for-lock to wait for it", output);
+            exit(1);
+        }
+        Ok(lock) => lock.flatten(),
+        Err(e) => {
+            error!("Unable to lock {:?}: {e}", output);
+            exit(1);
+        }
+    };
+    if merge(&matches, "clean_partial", cli.clean_partial, file.clean_partial) && !cli.dry_run {
+        let removed = remove_partial_files(Path::new(&output));
+        info!("Removed {removed} partial downloads from {:?}", output);
+    }
+    // --overwrite and the overwrite key are shorthands for --on-existing overwrite
+    let overwrite = merge(&matches, "overwrite", cli.overwrite, file.overwrite).then_some(OnExisting::Overwrite);
+    let on_existing = match matches.value_source("on_existing") {
+        Some(ValueSource::CommandLine) => cli.on_existing,
+        _ => overwrite.or(file.on_existing).unwrap_or(cli.on_existing),
+    };
+    let config = DownloaderConfig {
+        concurrency: merge(&matches, "concurrency", cli.concurrency, file.concurrency),
+        show_progress: !quiet,
+        on_existing,
+        filename_template: merge(&matches, "filename_template", cli.filename_template, file.filename_template),
+        directory_template: merge(
+            &matches,
+            "directory_template",
+            cli.directory_template,
+            file.directory_template.map(Some)
+        ),
+        artist_separator: merge(&matches, "artist_separator", cli.artist_separator, file.artist_separator),
+        featured_artists: merge(&matches, "featured_artists", cli.featured_artists, file.featured_artists),
+        album_artist_separator: merge(
+            &matches,
+            "album_artist_separator",
+            cli.album_artist_separator,
+            file.album_artist_separator
+        ),
+        genre_count: merge(&matches, "genres", cli.genres, file.genres),
+        lyrics: merge(&matches, "lyrics", cli.lyrics, file.lyrics),
+        embed_lyrics: merge(&matches, "embed_lyrics", cli.embed_lyrics, file.embed_lyrics),
+        credits: merge(&matches, "credits", cli.credits, file.credits),
+        extended_tags: merge(&matches, "extended_tags", cli.extended_tags, file.extended_tags),
+        dedup_isrc: merge(&matches, "dedup_isrc", cli.dedup_isrc, file.dedup_isrc),
+        verify: merge(&matches, "verify", cli.verify, file.verify),
+        embed_url: !merge(&matches, "no_url", cli.no_url, file.no_url),
+        save_cover: merge(&matches, "save_cover", cli.save_cover, file.save_cover),
+        save_metadata: merge(&matches, "save_metadata", cli.save_metadata, file.save_metadata),
+        nfo: merge(&matches, "nfo", cli.nfo, file.nfo),
+        cover_size: merge(&matches, "cover_size", cli.cover_size, file_cover_size),
+        cover_max_size: merge(&matches, "cover_max_size", cli.cover_max_size, file.cover_max_size.map(Some)),
+        cover_quality: merge(&matches, "cover_quality", cli.cover_quality, file.cover_quality),
+        format_preference,
+        min_quality: merge(&matches, "min_quality", cli.min_quality, file_min_quality.map(Some)),
+        strict_quality: merge(&matches, "strict_quality", cli.strict_quality, file.strict_quality),
+        force_compilation: merge(&matches, "compilation", cli.compilation, file.compilation),
+        album_playlist: merge(&matches, "album_playlist", cli.album_playlist, file.album_playlist),
+        cue: merge(&matches, "cue", cli.cue, file.cue),
+        playlist_as_album: merge(&matches, "playlist_as_album", cli.playlist_as_album, file.playlist_as_album),
+        release_types: {
+            let excluded = merge(&matches, "exclude_types", cli.exclude_types, file.exclude_types);
+            merge(&matches, "include_types", cli.include_types, file.include_types)
+                .into_iter()
+                .filter(|release_type| !excluded.contains(release_type))
+                .collect()
+        },
+        dry_run: cli.dry_run,
+        retag: matches!(cli.command, Some(Command::Retag { .. })),
+        cover_only: cli.cover_only,
+        space_check: !merge(&matches, "no_space_check", cli.no_space_check, file.no_space_check),
+        market: merge(&matches, "market", cli.market, file.market.map(Some)).map(|market| market.to_uppercase()),
+        fallback_markets: merge(&matches, "fallback_markets", cli.fallback_markets, file.fallback_markets)
+            .iter()
+            .map(|market| market.to_uppercase())
+            .collect(),
+        max_rate: merge(&matches, "max_rate", cli.max_rate, file.max_rate.map(Some)),
+        prefetch: merge(&matches, "prefetch", cli.prefetch, file.prefetch),
+        prefer: merge(&matches, "prefer", cli.prefer, file.prefer.map(Some)),
+        cover_headers: merge(&matches, "cover_headers", cli.cover_headers, file_cover_headers),
+        track_timeout: merge(&matches, "track_timeout", cli.track_timeout, file.track_timeout.map(Some))
+            .map(Duration::from_secs),
+        request_timeout: Duration::from_secs(merge(&matches, "request_timeout", cli.request_timeout, file.request_timeout)),
+        retry_attempts: merge(&matches, "retries", cli.retries, file.retries).saturating_add(1),
+        retry_base_delay: Duration::from_millis(merge(&matches, "retry_delay", cli.retry_delay, file.retry_delay)),
+        #[cfg(feature = "transcode")]
+        transcode: cli.transcode.map(|format| Transcode {
+            format,
+            bitrate: cli.transcode_bitrate.clone(),
+            replace: cli.transcode_replace,
+        }),
+        #[cfg(feature = "transcode")]
+        concat: cli.concat,
+        #[cfg(feature = "pcm")]
+        pcm: cli.pcm,
+        cover_cache: cache_dir.as_ref().map(|cache_dir| cache_dir.join(COVER_CACHE)),
+        ..DownloaderConfig::default()
+    };
+    #[cfg(feature = "sqlite")]
+    let config = match merge(&matches, "index", cli.index, file.index.map(Some)) {
+        Some(path) => match TrackIndex::open(&path) {
+            Ok(index) => DownloaderConfig { index: Some(index), ..config },
+            Err(e) => {
+                error!("Unable to open the index {:?}: {e}", path);
+                exit(1);
+            }
+        },
+        None => config,
+    };
+    if let Err(e) = config.validate() {
+        error!("Invalid configuration: {e}");
+        exit(1);
+    }
+    #[cfg(feature = "transcode")]
+    if (config.transcode.is_some() || config.concat) && !Transcode::ffmpeg_available() {
+        error!("--transcode and --concat require ffmpeg, but it was not found on PATH");
+        exit(1);
+    }
 
-        track.files.iter().for_each(|file| {
-            debug!("<{}> has format {:?}", track.id, file.0);
-        });
+    let mut references = cli.references.clone();
+    if let Some(Command::Retag { references: retagged }) = &cli.command {
+        references.extend(retagged.iter().cloned());
+    }
+    if let Some(input_file) = &cli.input_file {
+        match read_references(input_file) {
+            Ok(listed) => references.extend(listed),
+            Err(e) => {
+                error!("Unable to read references from {:?}: {e}", input_file);
+                exit(1);
+            }
+        }
+    }
 
-        let (format, file_id) = match FORMAT_PREFERENCE
-            .iter()
-            .find_map(|format| {
-                track.files
-                .get(format)
-                .map(|file_id| (*format, file_id.clone()))
-            })
-        {
-            Some(format) => {
-                debug!("<{}> selected format {:?}", track.id, &format.0);
-                format
+    let retag = matches!(cli.command, Some(Command::Retag { .. }));
+    // Every downloading run saves its progress, so any of them can be resumed
+    let resume_path = Path::new(&output).join(RESUME_FILE);
+    let resume = match (writes_output && !retag && !cli.cover_only, cli.resume) {
+        (false, _) => Ok(None),
+        (true, false) => ResumeState::create(&resume_path, references.clone()).map(Some),
+        (true, true) => ResumeState::load(&resume_path).and_then(|resume| {
+            for reference in &references {
+                resume.push_reference(reference)?;
+            }
+            Ok(Some(resume))
+        }),
+    };
+    let resume = match resume {
+        Ok(resume) => resume.map(Arc::new),
+        Err(e) => {
+            error!("Unable to save the progress to {:?}: {e}", resume_path);
+            exit(1);
+        }
+    };
+    if let Some(resume) = resume.as_ref().filter(|_| cli.resume) {
+        references = resume.references();
+        info!("Resuming with {} references left", references.len());
+    }
+    let config = DownloaderConfig { resume: resume.clone(), ..config };
+
+    let stdout_uri = if cli.stdout {
+        match references.as_slice() {
+            [reference] => match parse_reference(reference) {
+                Ok(uri @ (SpotifyUri::Track { .. } | SpotifyUri::Episode { .. })) => Some(uri),
+                Ok(uri) => {
+                    error!("--stdout only downloads tracks and episodes, not <{}>", uri);
+                    exit(1);
+                }
+                Err(e) => {
+                    error!("Invalid reference {:?}: {e}", reference);
+                    exit(1);
+                }
             },
-            None => {
-                warn!("<{}> is not available in any supported format", track.id);
-                return Ok(());
+            _ => {
+                error!("--stdout takes exactly one reference");
+                exit(1);
             }
-        };
-        let bytes_per_second = format_data_rate(format);
-        let encrypted_file = AudioFile::open(&self.session, file_id, bytes_per_second);
-        let encrypted_file = match encrypted_file.await {
-            Ok(encrypted_file) => encrypted_file,
+        }
+    } else {
+        None
+    };
+
+    let cache_dirs: Vec<Option<PathBuf>> = if cache_dir.is_none() {
+        vec![None]
+    } else if accounts.is_empty() {
+        vec![cache_dir.clone()]
+    } else {
+        accounts
+            .iter()
+            .map(|account| cache_dir.as_ref().map(|dir| dir.join(ACCOUNTS_CACHE).join(sanitize_filename(account, ""))))
+            .collect()
+    };
+    let mut sessions = Vec::new();
+    for cache_dir in &cache_dirs {
+        match cache_dir {
+            Some(cache_dir) => info!("Connecting with {:?}...", cache_dir),
+            None => info!("Connecting without a cache..."),
+        }
+        match connect(cache_dir.as_deref(), manual_login, proxy.as_ref()).await {
+            Ok(session) => sessions.push(session),
             Err(e) => {
-                error!("Unable to load encrypted file: {e:?}");
-                return Ok(());
+                error!("Error connecting: {e}");
+                exit(EXIT_AUTH_FAILURE);
             }
-        };
-        let stream_loader_controller = encrypted_file.get_stream_loader_controller()?;
-        let key = match self.session.audio_key().request(track_id, file_id).await {
-            Ok(key) => Some(key),
+        }
+    }
+
+    let mut sessions = sessions.into_iter();
+    let Some(session) = sessions.next() else {
+        return Ok(());
+    };
+    let mut downloader = Downloader::with_config(session, config);
+    sessions.for_each(|session| downloader.add_session(session));
+    let cancel = CancellationToken::new();
+    downloader.set_cancellation_token(cancel.clone());
+    tokio::spawn({
+        let cancel = cancel.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                warn!("Interrupted, stopping the running downloads");
+                cancel.cancel();
+            }
+        }
+    });
+    if let Some(Command::Info { references } | Command::ListFormats { references }) = &cli.command {
+        let list_formats = matches!(cli.command, Some(Command::ListFormats { .. }));
+        let mut failed = 0;
+        for reference in references {
+            let uri = match parse_reference(reference) {
+                Ok(uri) => uri,
+                Err(e) => {
+                    error!("Invalid reference {:?}: {e}", reference);
+                    failed += 1;
+                    continue;
+                }
+            };
+            let described =
+                if list_formats { downloader.list_formats(&uri).await } else { downloader.info(&uri).await };
+            match described {
+                Ok(text) => println!("{text}"),
+                Err(e) => {
+                    error!("<{}> unable to describe: {e}", uri);
+                    failed += 1;
+                }
+            }
+        }
+        if failed == references.len() {
+            exit(EXIT_NOTHING_RESOLVED);
+        } else if failed > 0 {
+            exit(EXIT_PARTIAL_FAILURE);
+        }
+        return Ok(());
+    }
+    if let Some(uri) = &stdout_uri {
+        if let Err(e) = downloader.download_to_writer(uri, io::stdout()).await {
+            error!("<{}> download failed: {e}", uri);
+            exit(if cancel.is_cancelled() { EXIT_INTERRUPTED } else { EXIT_NOTHING_RESOLVED });
+        }
+        return Ok(());
+    }
+    let mut outcomes = Vec::new();
+    let mut failed_references = 0;
+    if cli.liked {
+        match downloader.download_saved_tracks(&output).await {
+            Ok(saved) => outcomes.extend(saved),
             Err(e) => {
-                warn!("Unable to load key, continuing without decryption: {e}");
-                None
+                error!("Unable to download Liked Songs: {e}");
+                failed_references += 1;
             }
-        };
-        let decrypted_file = AudioDecrypt::new(key, encrypted_file);
-        let offset = if AudioFiles::is_ogg_vorbis(format) { SPOTIFY_OGG_HEADER_END } else { 0 };
-        let mut audio_file = match Subfile::new(decrypted_file, offset, stream_loader_controller.len() as u64) {
-            Ok(audio_file) => audio_file,
+        }
+    }
+    for reference in &references {
+        if cancel.is_cancelled() {
+            break;
+        }
+        if cli.cover_only && Path::new(reference).is_dir() {
+            outcomes.extend(downloader.backfill_covers(Path::new(reference)).await);
+            continue;
+        }
+        let uri = match parse_reference(reference) {
+            Ok(uri) => Some(uri),
             Err(e) => {
-                error!("PlayerTrackLoader::download_track error opening subfile: {e}");
-                return Ok(());
+                error!("Invalid reference {:?}: {e}", reference);
+                failed_references += 1;
+                None
             }
         };
-        self.save_decrypted_audio(format, &track, &mut audio_file, &dirpath).await?;
-        Ok(())
-    }
-
-    async fn save_decrypted_audio(
-        &mut self,
-        format: AudioFileFormat,
-        track: &Track,
-        audio_file: &mut Subfile<AudioDecrypt<AudioFile>>,
-        dirpath: &PathBuf
-    ) -> Result<(), Error> {
-        let file_extension = get_extension_from_format(format);
-        let artists = track.artists.iter().map(|a| a.name.as_str()).collect::<Vec<_>>().join(" & ");
-        
-        let mut filepath = dirpath.clone();
-        let filename = format!("{} - {} ({}).{}", artists, track.name, track.id.to_id()?, file_extension);
-        filepath.push(filename);
-        let mut outfile = File::create(&filepath)?;
-        copy(audio_file, &mut outfile)?;
-        info!("Decrypted content saved to {:?}", filepath);
-
-        self.apply_tag(file_extension, track, artists, filepath).await?;
-
-        Ok(())
-    }
-
-    async fn apply_tag(
-        &mut self,
-        file_extension: String,
-        track: &Track,
-        artists: String,
-        filepath: PathBuf
-    ) -> Result<(), Error> {
-        let tag_type = match file_extension.as_str() {
-            "ogg" | "flac" => TagType::VorbisComments,
-            _ => TagType::Id3v2,
+        // Failed references stay queued, invalid ones would fail again
+        let attempted = match &uri {
+            Some(uri) => match downloader.download_uri(uri, &output).await {
+                Ok(downloaded) => {
+                    outcomes.extend(downloaded);
+                    true
+                }
+                Err(e) => {
+                    error!("<{}> download failed: {e}", uri);
+                    failed_references += 1;
+                    false
+                }
+            },
+            None => true,
         };
-        let mut tag = Tag::new(tag_type);
-        tag.insert(TagItem::new(ItemKey::TrackTitle, ItemValue::Text(track.name.clone())));
-        tag.insert(TagItem::new(ItemKey::AlbumTitle, ItemValue::Text(track.album.name.clone())));
-        tag.insert(TagItem::new(ItemKey::TrackArtist, ItemValue::Text(artists)));
-        tag.insert(TagItem::new(ItemKey::TrackNumber, ItemValue::Text(track.number.to_string())));
-        tag.insert(TagItem::new(ItemKey::Isrc, ItemValue::Text(track.id.to_uri()?)));
-        // tag.insert(TagItem::new(ItemKey::RecordingDate, ItemValue::Text(year)));
-
-        let (cover_data, mime_type) = self.get_cover(track).await?;
-        let picture = Picture::new_unchecked(
-            PictureType::CoverFront,
-            Some(mime_type),
-            Some("cover".to_string()),
-            cover_data
-        );
-        tag.push_picture(picture);
-
-        if let Err(e) = tag.save_to_path(&filepath, WriteOptions::default()) {
-            warn!("Unable to write metadata to {:?}: {}", filepath, e);
-        } else {
-            debug!("Metadata written to {:?}", filepath);
+        if let Some(resume) = resume.as_ref().filter(|_| attempted && !cancel.is_cancelled()) {
+            if let Err(e) = resume.finish_reference(reference) {
+                warn!("Unable to save the progress to {:?}: {e}", resume_path);
+            }
         }
-
-        Ok(())
     }
-
-    async fn get_cover(&mut self, track: &Track) -> Result<(Vec<u8>, MimeType), Error> {
-        fn size_rank(size: image::ImageSize) -> i32 {
-            match size {
-                image::ImageSize::DEFAULT => 0,
-                image::ImageSize::SMALL => 1,
-                image::ImageSize::LARGE => 2,
-                image::ImageSize::XLARGE => 3,
+    let mut downloaded = 0;
+    let mut existing = 0;
+    let mut cancelled = 0;
+    let mut downgraded = 0;
+    let mut overwritten = 0;
+    let mut renamed = 0;
+    let mut retagged = 0;
+    let mut not_downloaded = 0;
+    let mut duplicates = 0;
+    let mut corrupt = 0;
+    let mut unavailable = 0;
+    let mut failed = 0;
+    let mut bytes = 0;
+    for (uri, outcome) in &outcomes {
+        if let TrackOutcome::Downloaded(file) | TrackOutcome::DryRun { file, .. } = outcome {
+            if file.below_min_quality {
+                warn!("<{}> {} in {:?}, below the minimum quality", uri, file.path.display(), file.format);
+                downgraded += 1;
+            }
+        }
+        if let TrackOutcome::Downloaded(file) = outcome {
+            match file.on_existing {
+                Some(OnExisting::Overwrite) => {
+                    info!("<{}> overwrote {}", uri, file.path.display());
+                    overwritten += 1;
+                }
+                Some(OnExisting::Rename) => {
+                    info!("<{}> saved as {} next to the existing file", uri, file.path.display());
+                    renamed += 1;
+                }
+                _ => {}
+            }
+        }
+        match outcome {
+            TrackOutcome::Downloaded(file) => {
+                downloaded += 1;
+                bytes += file.path.metadata().map_or(0, |metadata| metadata.len());
+            }
+            TrackOutcome::SkippedExisting(_) => existing += 1,
+            TrackOutcome::SkippedUnsupportedFormat => {
+                warn!("<{}> skipped: no supported format", uri);
+                unavailable += 1;
+            }
+            TrackOutcome::SkippedBelowMinQuality(format) => {
+                warn!("<{}> skipped: only available in {:?}, below the minimum quality", uri, format);
+                unavailable += 1;
+            }
+            TrackOutcome::Failed(e) => {
+                warn!("<{}> failed: {e}", uri);
+                if matches!(e, DownloadError::Corrupt(_)) {
+                    corrupt += 1;
+                }
+                failed += 1;
+            }
+            TrackOutcome::Cancelled => cancelled += 1,
+            TrackOutcome::Retagged(_) => retagged += 1,
+            TrackOutcome::SkippedNotDownloaded => not_downloaded += 1,
+            TrackOutcome::SkippedDuplicate(_) => duplicates += 1,
+            TrackOutcome::DryRun { file, exists } => {
+                let status = match file.on_existing {
+                    _ if *exists => "exists",
+                    Some(OnExisting::Overwrite) => "overwrite",
+                    Some(OnExisting::Rename) => "rename",
+                    _ => "new",
+                };
+                println!("[{status}] {} ({:?}) -> {}", file.title, file.format, file.path.display());
+                if *exists {
+                    existing += 1;
+                } else {
+                    downloaded += 1;
+                }
             }
         }
-        let cover = track.album.covers.iter().max_by_key(|cover| size_rank(cover.size)).unwrap();
-        let cover_id = cover.id.to_string();
-        if !self.album_cover_cache.contains_key(&cover_id) {
-            self.download_cover(&cover_id).await?;
-        }
-        let (cover_data, mime_type) = self.album_cover_cache.get(&cover_id).unwrap();
-        Ok((cover_data.clone(), mime_type.clone()))
-    }   
-
-    async fn download_cover(&mut self, id: &String) -> Result<(), Error> {
-        let request = Request::builder()
-            .method(&Method::GET)
-            .uri(format!("{}{}", IMAGE_URL, id))
-            .header(ACCEPT, HeaderValue::from_static("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"))
-            .body(Bytes::new())?;
-        let body = self.session.http_client().request_body(request).await?;
-        let cover_data = body.to_vec();
-        let mime_type = infer::get(&cover_data)
-            .map(|t| MimeType::from_str(t.mime_type()))
-            .unwrap_or(MimeType::Jpeg);
-        self.album_cover_cache.insert(id.clone(), (cover_data, mime_type));
-        Ok(())
     }
-}
-
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    env_logger::builder()
-        .filter_module("librespot", LevelFilter::Debug)
-        .init();
-
-    let session_config = SessionConfig::default();
-
-    let cache = Cache::new(Some(CACHE), Some(CACHE), Some(CACHE_FILES), None)?;
-    let credentials = cache
-        .credentials()
-        .ok_or(Error::unavailable("credentials not cached"))
-        .or_else(|_| {
-            OAuthClientBuilder::new(
-                &session_config.client_id,
-                "http://127.0.0.1:8898/login",
-                vec!["streaming"],
-            )
-            .open_in_browser()
-            .build()?
-            .get_access_token()
-            .map(|t| Credentials::with_access_token(t.access_token))
-        })?;
-
-    info!("Connecting...");
-    let session = Session::new(session_config, Some(cache));
-    if let Err(e) = session.connect(credentials, true).await {
-        info!("Error connecting: {e}");
-        exit(1);
+    if cli.dry_run {
+        info!("{downloaded} to download, {existing} already present, {} total", outcomes.len());
+    } else if cli.cover_only {
+        info!("{retagged} covers added, {existing} already present, {not_downloaded} not downloaded");
+    } else if matches!(cli.command, Some(Command::Retag { .. })) {
+        info!("{retagged} retagged, {not_downloaded} not downloaded, {} total", outcomes.len());
+    } else {
+        info!("{downloaded} downloaded, {existing} already present, {} total", outcomes.len());
+    }
+    if failed_references > 0 {
+        warn!("{failed_references} references failed");
+    }
+    if overwritten > 0 || renamed > 0 {
+        info!("{overwritten} existing files overwritten, {renamed} saved under a new name");
+    }
+    if downgraded > 0 {
+        warn!("{downgraded} below the minimum quality");
+    }
+    if cancelled > 0 {
+        warn!("{cancelled} cancelled");
+    }
+    if duplicates > 0 {
+        info!("{duplicates} skipped as duplicates of an already downloaded recording");
+    }
+    if corrupt > 0 {
+        warn!("{corrupt} failed verification twice and were not saved");
+    }
+    if let Some(resume) = &resume {
+        if cancel.is_cancelled() || failed + failed_references > 0 {
+            info!("Pass --resume to continue where this run stopped");
+        } else if let Err(e) = resume.clear() {
+            warn!("Unable to remove {:?}: {e}", resume_path);
+        }
+    }
+    if let Some(manifest) = &manifest {
+        if let Err(e) = write_manifest(manifest, &outcomes) {
+            error!("Unable to write manifest to {:?}: {e}", manifest);
+        }
+    }
+    if !quiet && !cli.dry_run {
+        let elapsed = started.elapsed();
+        let skipped = existing + unavailable + not_downloaded + duplicates;
+        eprintln!("Downloaded {downloaded}, skipped {skipped}, failed {}", failed + failed_references);
+        eprintln!(
+            "{} written in {}, {}/s on average",
+            HumanBytes(bytes),
+            HumanDuration(elapsed),
+            HumanBytes((bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64)
+        );
     }
-    
-    let mut downloader = Downloader::new(session);
-    downloader.download_album_by_id("2FRgTjahtyzUQG8A3ZaaDT", "downloads").await?;
 
+    let requested = !references.is_empty() || cli.liked;
+    if cancel.is_cancelled() {
+        exit(EXIT_INTERRUPTED);
+    } else if requested && outcomes.is_empty() {
+        exit(EXIT_NOTHING_RESOLVED);
+    } else if failed + failed_references > 0 {
+        exit(EXIT_PARTIAL_FAILURE);
+    }
     Ok(())
 }